@@ -0,0 +1,256 @@
+extern crate libc;
+
+use std::sync::mpsc::Receiver;
+use std::error::Error;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use super::{ClipboardBackend, ClipboardContent, MemoryClipboard, Selection};
+
+/// Returns the path of the control socket: PASTE_ANYWHERE_SOCKET if set, otherwise
+/// paste-anywhere.sock in XDG_RUNTIME_DIR or, failing that, in a directory of the temporary
+/// directory that only we may access.
+pub fn socket_path() -> Result<PathBuf, Box<Error>> {
+    if let Some(path) = env::var_os("PASTE_ANYWHERE_SOCKET") {
+        return Ok(PathBuf::from(path));
+    }
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Ok(Path::new(&dir).join("paste-anywhere.sock")),
+        None => {
+            // Other users could take the place of a socket right in the temporary directory, and
+            // receive whatever we copy.
+            let dir = env::temp_dir().join(format!("paste-anywhere-{}", uid()));
+            private_dir(&dir)?;
+            Ok(dir.join("paste-anywhere.sock"))
+        }
+    }
+}
+
+/// Returns the user ID we are running as.
+fn uid() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+/// Creates the directory [dir] so that only we may access it, or checks that an existing one is
+/// only accessible by us.
+fn private_dir(dir: &Path) -> Result<(), Box<Error>> {
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => return Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+        Err(e) => return Err(From::from(format!("unable to create {}: {}", dir.display(), e))),
+    }
+    let meta = fs::symlink_metadata(dir)?;
+    if !meta.is_dir() || meta.uid() != uid() || meta.mode() & 0o077 != 0 {
+        return Err(From::from(format!(
+            "{} is not a directory only we may access",
+            dir.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that the socket at [path], if there is one, belongs to us rather than another user.
+fn check_owner(path: &Path) -> Result<(), Box<Error>> {
+    match fs::symlink_metadata(path) {
+        Ok(ref meta) if meta.uid() != uid() => Err(From::from(format!(
+            "{} belongs to another user",
+            path.display()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// A HeadlessClipboard is a ClipboardBackend for nodes without a display.
+/// The selections are kept in memory and driven through a Unix socket, which the `copy` and
+/// `paste` functions (and subcommands) talk to.
+///
+/// The protocol is a single request line, `copy <selection> <target>` followed by the data until
+/// EOF, or `paste <selection> <target>`.
+/// The response is a line, `ok` or `error <reason>`, followed by the data for `paste`.
+pub struct HeadlessClipboard {
+    memory: MemoryClipboard,
+}
+
+impl HeadlessClipboard {
+    /// Starts serving the selections on the socket at [path].
+    /// A stale socket left behind by a previous run is replaced, a live one is an error.
+    pub fn init(path: &Path) -> Result<Self, Box<Error>> {
+        check_owner(path)?;
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(From::from(format!(
+                    "another instance is listening on {}",
+                    path.display()
+                )));
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        println!("clipboard: headless, listening on {}", path.display());
+
+        let memory = MemoryClipboard::new();
+        {
+            let memory = memory.clone();
+            thread::spawn(move || for stream in listener.incoming() {
+                match stream {
+                    Err(e) => println!("clipboard: unable to accept control connection: {}", e),
+                    Ok(stream) => {
+                        let memory = memory.clone();
+                        thread::spawn(move || if let Err(e) = serve(stream, &memory) {
+                            println!("clipboard: control connection failed: {}", e);
+                        });
+                    }
+                }
+            });
+        }
+
+        Ok(HeadlessClipboard { memory: memory })
+    }
+}
+
+/// Handles a single request on [stream].
+fn serve(stream: UnixStream, memory: &MemoryClipboard) -> Result<(), Box<Error>> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let parts: Vec<&str> = line.trim_right().split(' ').collect();
+    if parts.len() != 3 {
+        writer.write_all(format!("error malformed request {:?}\n", line).as_bytes())?;
+        return Ok(());
+    }
+    let selection: Selection = match parts[1].parse() {
+        Ok(selection) => selection,
+        Err(e) => {
+            writer.write_all(format!("error {}\n", e).as_bytes())?;
+            return Ok(());
+        }
+    };
+    let target = parts[2];
+
+    match parts[0] {
+        "copy" => {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            let mut content = ClipboardContent::new();
+            content.insert(target, data);
+            memory.copy(selection, content);
+            writer.write_all(b"ok\n")?;
+        }
+        "paste" => {
+            let content = memory.paste(selection);
+            match content.get(target) {
+                Some(data) => {
+                    writer.write_all(b"ok\n")?;
+                    writer.write_all(data)?;
+                }
+                None => writer.write_all(
+                    format!(
+                        "error {:?} has no {}, available: {:?}\n",
+                        selection,
+                        target,
+                        content.targets()
+                    ).as_bytes(),
+                )?,
+            }
+        }
+        cmd => writer.write_all(format!("error unknown command {}\n", cmd).as_bytes())?,
+    }
+    Ok(())
+}
+
+/// Sends a request to the node listening at [path] and returns the data of the response.
+fn request(path: &Path, line: &str, data: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+    check_owner(path)?;
+    let mut stream = UnixStream::connect(path)
+        .map_err(|e| format!("unable to connect to {}: {}", path.display(), e))?;
+    stream.write_all(line.as_bytes())?;
+    stream.write_all(b"\n")?;
+    stream.write_all(data)?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    let status = status.trim_right();
+    if status != "ok" {
+        return Err(From::from(status.trim_left_matches("error ").to_string()));
+    }
+
+    let mut resp = Vec::new();
+    reader.read_to_end(&mut resp)?;
+    Ok(resp)
+}
+
+/// Copies [data] as [target] into [selection] of the headless node listening at [path], which
+/// publishes it to the overlay.
+pub fn copy(path: &Path, selection: Selection, target: &str, data: &[u8]) -> Result<(), Box<Error>> {
+    request(path, &format!("copy {} {}", selection, target), data)?;
+    Ok(())
+}
+
+/// Returns [selection] as [target] from the headless node listening at [path].
+pub fn paste(path: &Path, selection: Selection, target: &str) -> Result<Vec<u8>, Box<Error>> {
+    request(path, &format!("paste {} {}", selection, target), &[])
+}
+
+impl ClipboardBackend for HeadlessClipboard {
+    fn get(&mut self, selection: Selection) -> Result<ClipboardContent, Box<Error>> {
+        self.memory.get(selection)
+    }
+
+    fn set(&mut self, selection: Selection, content: ClipboardContent) -> Result<(), Box<Error>> {
+        self.memory.set(selection, content)
+    }
+
+    fn subscribe(&mut self) -> Receiver<(Selection, ClipboardContent)> {
+        self.memory.subscribe()
+    }
+}
+
+#[test]
+fn copy_and_paste_through_socket() {
+    use super::TEXT_TARGET;
+
+    let path = env::temp_dir().join(format!("paste-anywhere-test-{}.sock", ::std::process::id()));
+    let mut cb = HeadlessClipboard::init(&path).unwrap();
+    let changes = cb.subscribe();
+    // Only one node may serve a socket.
+    assert!(HeadlessClipboard::init(&path).is_err());
+
+    copy(&path, Selection::Primary, TEXT_TARGET, b"hello").unwrap();
+    assert_eq!(
+        changes.recv().unwrap(),
+        (Selection::Primary, ClipboardContent::from_text("hello"))
+    );
+
+    cb.set(Selection::Clipboard, ClipboardContent::from_text("world"))
+        .unwrap();
+    assert_eq!(paste(&path, Selection::Clipboard, TEXT_TARGET).unwrap(), b"world");
+    assert!(paste(&path, Selection::Clipboard, "image/png").is_err());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn fallback_directory_is_private() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = env::temp_dir().join(format!("paste-anywhere-test-{}", ::std::process::id()));
+    private_dir(&dir).unwrap();
+    assert_eq!(fs::metadata(&dir).unwrap().mode() & 0o777, 0o700);
+    // Reusing it is fine, as long as nobody else may get in.
+    private_dir(&dir).unwrap();
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+    assert!(private_dir(&dir).is_err());
+
+    fs::remove_dir(&dir).unwrap();
+}
@@ -0,0 +1,97 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::error::Error;
+
+use super::{ClipboardBackend, ClipboardContent, Selection};
+
+struct Inner {
+    contents: HashMap<Selection, ClipboardContent>,
+    subscribers: Vec<Sender<(Selection, ClipboardContent)>>,
+}
+
+/// A MemoryClipboard is a ClipboardBackend that keeps its selections in memory, so nodes can run
+/// without a display.
+/// Clones share the same selections: one clone can be handed to the overlay while another one
+/// plays the part of the other applications on the node, using `copy` and `paste`.
+#[derive(Clone)]
+pub struct MemoryClipboard {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl MemoryClipboard {
+    /// Creates a MemoryClipboard with empty selections.
+    pub fn new() -> MemoryClipboard {
+        MemoryClipboard {
+            inner: Arc::new(Mutex::new(Inner {
+                contents: HashMap::new(),
+                subscribers: Vec::new(),
+            })),
+        }
+    }
+
+    /// Sets [selection] to [content] the way another application would, i.e. the change is
+    /// reported to subscribers.
+    pub fn copy(&self, selection: Selection, content: ClipboardContent) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.contents.get(&selection) == Some(&content) {
+            return;
+        }
+        inner.contents.insert(selection, content.clone());
+        // Drop subscribers whose receiver is gone.
+        inner
+            .subscribers
+            .retain(|s| s.send((selection, content.clone())).is_ok());
+    }
+
+    /// Returns whether anybody subscribed to changes, i.e. whether a copy would be reported.
+    #[cfg(test)]
+    pub fn has_subscribers(&self) -> bool {
+        !self.inner.lock().unwrap().subscribers.is_empty()
+    }
+
+    /// Returns the current content of [selection] the way another application would see it.
+    pub fn paste(&self, selection: Selection) -> ClipboardContent {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .contents
+            .get(&selection)
+            .cloned()
+            .unwrap_or(ClipboardContent::new())
+    }
+}
+
+impl ClipboardBackend for MemoryClipboard {
+    fn get(&mut self, selection: Selection) -> Result<ClipboardContent, Box<Error>> {
+        Ok(self.paste(selection))
+    }
+
+    fn set(&mut self, selection: Selection, content: ClipboardContent) -> Result<(), Box<Error>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.contents.insert(selection, content);
+        Ok(())
+    }
+
+    fn subscribe(&mut self) -> Receiver<(Selection, ClipboardContent)> {
+        let (sendr, recvr) = channel();
+        self.inner.lock().unwrap().subscribers.push(sendr);
+        recvr
+    }
+}
+
+#[test]
+fn copy_is_reported_set_is_not() {
+    let mut cb = MemoryClipboard::new();
+    let changes = cb.subscribe();
+    let text = ClipboardContent::from_text("hello");
+
+    cb.set(Selection::Clipboard, text.clone()).unwrap();
+    assert_eq!(cb.paste(Selection::Clipboard), text);
+    assert!(changes.try_recv().is_err());
+
+    let other = ClipboardContent::from_text("world");
+    cb.clone().copy(Selection::Primary, other.clone());
+    assert_eq!(cb.get(Selection::Primary).unwrap(), other);
+    assert_eq!(changes.try_recv().unwrap(), (Selection::Primary, other));
+    assert_eq!(cb.get(Selection::Clipboard).unwrap(), text);
+}
@@ -1,85 +1,275 @@
-extern crate x11_clipboard;
-
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::Receiver;
+use std::collections::BTreeMap;
+use std::env;
 use std::error::Error;
-use self::x11_clipboard::Clipboard as X11CB;
+use std::fmt;
+use std::str::FromStr;
+
+mod x11;
+mod wayland;
+mod memory;
+pub mod headless;
+
+pub use self::x11::X11Clipboard;
+pub use self::wayland::WaylandClipboard;
+pub use self::memory::MemoryClipboard;
+pub use self::headless::HeadlessClipboard;
+
+/// The target name used for UTF-8 text.
+/// Targets are identified by MIME type; X11's UTF8_STRING is translated to and from this.
+pub const TEXT_TARGET: &'static str = "text/plain;charset=utf-8";
+
+/// The targets requested from remote peers by default, most preferred first.
+/// Plain text comes first because it can be pasted nearly everywhere.
+pub const DEFAULT_TARGETS: &'static [&'static str] = &[
+    TEXT_TARGET,
+    "text/uri-list",
+    "image/png",
+    "image/jpeg",
+    "text/html",
+    "text/rtf",
+];
 
-pub struct Clipboard {
-    x11_clipboard: X11CB,
-    last_content: String,
+/// A Selection is one of the X11 selections that are synchronized.
+/// CLIPBOARD is what copy/paste uses, PRIMARY is what select/middle-click paste uses.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Selection {
+    Clipboard,
+    Primary,
 }
 
-impl Clipboard {
-    pub fn init() -> Result<(Self, Receiver<String>), Box<Error>> {
-        let (sendr, recvr) = channel();
-        let cb = X11CB::new()?;
-        let mut obj=Clipboard { x11_clipboard: cb ,last_content: "".to_string()};
+impl Default for Selection {
+    fn default() -> Selection {
+        Selection::Clipboard
+    }
+}
 
-        obj.set_contents("".to_string());
-        Ok((obj, recvr))
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Selection::Clipboard => write!(f, "clipboard"),
+            Selection::Primary => write!(f, "primary"),
+        }
     }
+}
 
-    pub fn set_contents(&mut self, data: String) -> Result<(), Box<Error>> {
-        if self.last_content == data {
-            return Ok(())
+impl FromStr for Selection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Selection, String> {
+        match s {
+            "clipboard" => Ok(Selection::Clipboard),
+            "primary" => Ok(Selection::Primary),
+            _ => Err(format!("unknown selection {}, expected clipboard or primary", s)),
         }
-        self.last_content=data.clone();
-        Ok(self.x11_clipboard.store(
-            self.x11_clipboard.setter.atoms.clipboard,
-            self.x11_clipboard.setter.atoms.utf8_string,
-            data,
-        )?)
-    }
-
-    pub fn get_contents(&mut self) -> Result<Option<String>, Box<Error>> {
-        let s=String::from_utf8(self.x11_clipboard.load(
-            self.x11_clipboard.getter.atoms.clipboard,
-            self.x11_clipboard.getter.atoms.utf8_string,
-            self.x11_clipboard.getter.atoms.property,
-            None,
-        )?)?;
-        //if cached, don't return it!
-        if s==self.last_content{
-            return Ok(None);
+    }
+}
+
+/// A SyncMode determines which local selections a node synchronizes with the overlay.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Only one selection is synchronized.
+    Only(Selection),
+
+    /// Both selections are synchronized independently.
+    Both,
+
+    /// Local changes to the first selection are published as the second one, which is the only
+    /// one synchronized.
+    /// Remote content for the second selection is written to both local selections.
+    Mirror(Selection, Selection),
+}
+
+impl SyncMode {
+    /// Returns the selections whose overlay state this node follows.
+    pub fn synced(&self) -> Vec<Selection> {
+        match *self {
+            SyncMode::Only(s) => vec![s],
+            SyncMode::Both => vec![Selection::Clipboard, Selection::Primary],
+            SyncMode::Mirror(_, into) => vec![into],
+        }
+    }
+
+    /// Returns the selection a local change to [local] is published as, if any.
+    pub fn publish_as(&self, local: Selection) -> Option<Selection> {
+        match *self {
+            SyncMode::Only(s) if s == local => Some(s),
+            SyncMode::Only(_) => None,
+            SyncMode::Both => Some(local),
+            SyncMode::Mirror(_, into) => Some(into),
+        }
+    }
+
+    /// Returns the local selections remote content for [synced] is written to.
+    pub fn apply_to(&self, synced: Selection) -> Vec<Selection> {
+        match *self {
+            SyncMode::Mirror(from, into) if into == synced => vec![from, into],
+            _ => vec![synced],
         }
-        //update cache
-        self.last_content=s.clone();
-        Ok(Some(s))
     }
 }
 
+impl Default for SyncMode {
+    fn default() -> SyncMode {
+        SyncMode::Only(Selection::Clipboard)
+    }
+}
+
+impl FromStr for SyncMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SyncMode, String> {
+        match s {
+            "clipboard" => Ok(SyncMode::Only(Selection::Clipboard)),
+            "primary" => Ok(SyncMode::Only(Selection::Primary)),
+            "both" => Ok(SyncMode::Both),
+            "primary-to-clipboard" => Ok(SyncMode::Mirror(Selection::Primary, Selection::Clipboard)),
+            "clipboard-to-primary" => Ok(SyncMode::Mirror(Selection::Clipboard, Selection::Primary)),
+            _ => Err(format!(
+                "unknown sync mode {}, expected one of clipboard, primary, both, \
+                 primary-to-clipboard, clipboard-to-primary",
+                s
+            )),
+        }
+    }
+}
+
+/// A ClipboardContent holds the data of a clipboard in every target (format) it was offered in,
+/// keyed by target name.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct ClipboardContent {
+    targets: BTreeMap<String, Vec<u8>>,
+}
+
+impl ClipboardContent {
+    /// Creates an empty ClipboardContent.
+    pub fn new() -> ClipboardContent {
+        ClipboardContent {
+            targets: BTreeMap::new(),
+        }
+    }
+
+    /// Creates a ClipboardContent holding only [text].
+    pub fn from_text(text: &str) -> ClipboardContent {
+        let mut content = ClipboardContent::new();
+        content.insert(TEXT_TARGET, text.as_bytes().to_vec());
+        content
+    }
+
+    /// Sets the data for [target], replacing any previous data for that target.
+    pub fn insert(&mut self, target: &str, data: Vec<u8>) {
+        self.targets.insert(target.to_string(), data);
+    }
+
+    /// Returns the data for [target], if present.
+    pub fn get(&self, target: &str) -> Option<&[u8]> {
+        self.targets.get(target).map(|data| data.as_slice())
+    }
+
+    /// Returns the text content, if present and valid UTF-8.
+    pub fn text(&self) -> Option<String> {
+        self.get(TEXT_TARGET)
+            .and_then(|data| String::from_utf8(data.to_vec()).ok())
+    }
+
+    /// Returns the names of all targets present.
+    pub fn targets(&self) -> Vec<String> {
+        self.targets.keys().cloned().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// Returns the size of the data in all targets together.
+    pub fn len(&self) -> usize {
+        self.targets.values().map(|data| data.len()).sum()
+    }
+
+    /// Negotiates a target: returns the first of [accepted] that is present, i.e. the one the
+    /// requester prefers most.
+    pub fn best_target<S: AsRef<str>>(&self, accepted: &[S]) -> Option<String> {
+        accepted
+            .iter()
+            .map(|t| t.as_ref())
+            .find(|t| self.targets.contains_key(*t))
+            .map(|t| t.to_string())
+    }
+}
+
+impl fmt::Display for ClipboardContent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(text) = self.text() {
+            return write!(f, "{}", text);
+        }
+        let targets: Vec<String> = self.targets
+            .iter()
+            .map(|(t, data)| format!("{} ({} bytes)", t, data.len()))
+            .collect();
+        write!(f, "[{}]", targets.join(", "))
+    }
+}
+
+/// A ClipboardBackend gives access to the local selections of a node, e.g. through an X server.
+pub trait ClipboardBackend: Send {
+    /// Returns the current content of [selection].
+    fn get(&mut self, selection: Selection) -> Result<ClipboardContent, Box<Error>>;
+
+    /// Sets [selection] to [content].
+    /// Changes made this way are not reported to subscribers, because they come from the overlay.
+    fn set(&mut self, selection: Selection, content: ClipboardContent) -> Result<(), Box<Error>>;
+
+    /// Returns a receiver on which every change of a selection made by other applications is
+    /// reported.
+    fn subscribe(&mut self) -> Receiver<(Selection, ClipboardContent)>;
+}
+
+/// Initializes the backend for the display server of this session: Wayland if WAYLAND_DISPLAY
+/// is set, X11 if DISPLAY is set.
+/// Without a display, or if [headless] is set, the selections are driven through the control
+/// socket instead.
+pub fn init(headless: bool) -> Result<Box<ClipboardBackend>, Box<Error>> {
+    if !headless && env::var_os("WAYLAND_DISPLAY").is_some() {
+        println!("clipboard: using wayland");
+        Ok(Box::new(WaylandClipboard::init()?))
+    } else if !headless && env::var_os("DISPLAY").is_some() {
+        println!("clipboard: using X11");
+        Ok(Box::new(X11Clipboard::init()?))
+    } else {
+        println!("clipboard: no display, running headless");
+        Ok(Box::new(HeadlessClipboard::init(&headless::socket_path()?)?))
+    }
+}
+
+#[test]
+fn target_negotiation() {
+    let mut content = ClipboardContent::from_text("hello");
+    content.insert("text/html", b"<b>hello</b>".to_vec());
+
+    assert_eq!(
+        content.best_target(&["image/png", "text/html", TEXT_TARGET]),
+        Some("text/html".to_string())
+    );
+    assert_eq!(
+        content.best_target(DEFAULT_TARGETS),
+        Some(TEXT_TARGET.to_string())
+    );
+    assert_eq!(content.best_target(&["image/png"]), None);
+    assert_eq!(content.text(), Some("hello".to_string()));
+}
+
 #[test]
-fn basics() {
-    let (mut cb, recv) = Clipboard::init().unwrap();
-
-    println!("!-----------------------!");
-    let stuff = cb.get_contents().unwrap();
-    format!("{}\n", stuff);
-    println!("!-----------------------!");
-
-    let data = "HAHUHY";
-    cb.set_contents(data.to_string());
-    assert!(cb.get_contents().unwrap().eq(data));
-    assert!(cb.get_contents().unwrap().eq(data));
-
-    //    let data = format!("{:?}", Instant::now());
-    //    let clipboard = Clipboard::new().unwrap();
-    //
-    //    let atom_clipboard = clipboard.setter.atoms.clipboard;
-    //    let atom_utf8string = clipboard.setter.atoms.utf8_string;
-    //    let atom_property = clipboard.setter.atoms.property;
-    //
-    //    clipboard.store(atom_clipboard, atom_utf8string, data.as_bytes()).unwrap();
-    //
-    //    let output = clipboard.load(atom_clipboard, atom_utf8string, atom_property, None).unwrap();
-    //    assert_eq!(output, data.as_bytes());
-    //
-    //    let data = format!("{:?}", Instant::now());
-    //    clipboard.store(atom_clipboard, atom_utf8string, data.as_bytes()).unwrap();
-    //
-    //    let output = clipboard.load(atom_clipboard, atom_utf8string, atom_property, None).unwrap();
-    //    assert_eq!(output, data.as_bytes());
-    //
-    //    let output = clipboard.load(atom_clipboard, atom_utf8string, atom_property, None).unwrap();
-    //    assert_eq!(output, data.as_bytes());
+fn sync_modes() {
+    let mirror: SyncMode = "primary-to-clipboard".parse().unwrap();
+    assert_eq!(mirror.synced(), vec![Selection::Clipboard]);
+    assert_eq!(mirror.publish_as(Selection::Primary), Some(Selection::Clipboard));
+    assert_eq!(
+        mirror.apply_to(Selection::Clipboard),
+        vec![Selection::Primary, Selection::Clipboard]
+    );
+
+    let only = SyncMode::default();
+    assert_eq!(only.publish_as(Selection::Primary), None);
+    assert_eq!(only.publish_as(Selection::Clipboard), Some(Selection::Clipboard));
+    assert!("sideways".parse::<SyncMode>().is_err());
 }
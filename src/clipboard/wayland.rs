@@ -0,0 +1,438 @@
+extern crate libc;
+extern crate wayland_client;
+extern crate wayland_protocols_wlr;
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsFd, AsRawFd};
+use std::thread;
+use std::time::{Duration, Instant};
+use self::wayland_client::{Connection, Dispatch, EventQueue, Proxy, QueueHandle};
+use self::wayland_client::backend::ObjectId;
+use self::wayland_client::protocol::{wl_registry, wl_seat};
+use self::wayland_protocols_wlr::data_control::v1::client::zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1};
+use self::wayland_protocols_wlr::data_control::v1::client::zwlr_data_control_manager_v1::ZwlrDataControlManagerV1;
+use self::wayland_protocols_wlr::data_control::v1::client::zwlr_data_control_offer_v1::{self, ZwlrDataControlOfferV1};
+use self::wayland_protocols_wlr::data_control::v1::client::zwlr_data_control_source_v1::{self, ZwlrDataControlSourceV1};
+
+use super::{ClipboardBackend, ClipboardContent, Selection, TEXT_TARGET};
+
+/// A MIME type we offer in addition to the actual targets, so we can recognize our own offers.
+/// Reading our own offer would deadlock, because the thread reading it is the one that serves it.
+const MARKER_TARGET: &'static str = "application/x-paste-anywhere";
+
+/// Names other clients commonly use for UTF-8 text, all served with the TEXT_TARGET data.
+const TEXT_ALIASES: &'static [&'static str] = &["text/plain", "UTF8_STRING", "STRING", "TEXT"];
+
+/// How long a source may take to write a target into the pipe, in milliseconds.
+/// A source that never closes the pipe would otherwise block the event loop forever.
+const RECEIVE_TIMEOUT_MS: u64 = 5000;
+
+/// The content last loaded or set for each selection, and the subscribers to report changes to.
+struct Shared {
+    last_content: HashMap<Selection, ClipboardContent>,
+    subscribers: Vec<Sender<(Selection, ClipboardContent)>>,
+}
+
+/// The state of the event loop thread.
+struct State {
+    shared: Arc<Mutex<Shared>>,
+    seat: Option<wl_seat::WlSeat>,
+    manager: Option<ZwlrDataControlManagerV1>,
+    /// The MIME types announced so far for every live offer.
+    offers: HashMap<ObjectId, Vec<String>>,
+    /// Offers that became a selection since the last dispatch, to be loaded outside of dispatch.
+    pending: Vec<(Selection, ZwlrDataControlOfferV1)>,
+    finished: bool,
+}
+
+/// A WaylandClipboard is a ClipboardBackend for the clipboard and primary selection of a Wayland
+/// seat, using the wlr-data-control protocol.
+/// That protocol lets us watch and set the selections without having a focused surface, which is
+/// supported by wlroots-based compositors such as Sway.
+pub struct WaylandClipboard {
+    conn: Connection,
+    qh: QueueHandle<State>,
+    manager: ZwlrDataControlManagerV1,
+    device: ZwlrDataControlDeviceV1,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl WaylandClipboard {
+    /// Connects to the compositor given by WAYLAND_DISPLAY and starts watching the clipboard and
+    /// primary selection of the first seat for changes.
+    /// The selections present at this point are not reported as changes.
+    pub fn init() -> Result<Self, Box<Error>> {
+        let conn = Connection::connect_to_env()?;
+        let mut queue: EventQueue<State> = conn.new_event_queue();
+        let qh = queue.handle();
+        conn.display().get_registry(&qh, ());
+
+        let shared = Arc::new(Mutex::new(Shared {
+            last_content: HashMap::new(),
+            subscribers: Vec::new(),
+        }));
+        let mut state = State {
+            shared: shared.clone(),
+            seat: None,
+            manager: None,
+            offers: HashMap::new(),
+            pending: Vec::new(),
+            finished: false,
+        };
+        queue.roundtrip(&mut state)?;
+
+        let seat = state.seat.clone().ok_or("compositor has no seat")?;
+        let manager = state
+            .manager
+            .clone()
+            .ok_or("compositor does not support wlr-data-control")?;
+        let device = manager.get_data_device(&seat, &qh, ());
+
+        // The compositor announces the current selections right away, take them as a baseline.
+        queue.roundtrip(&mut state)?;
+        state.load_pending(&conn, false);
+
+        {
+            let conn = conn.clone();
+            thread::spawn(move || run(queue, state, conn));
+        }
+
+        Ok(WaylandClipboard {
+            conn: conn,
+            qh: qh,
+            manager: manager,
+            device: device,
+            shared: shared,
+        })
+    }
+}
+
+/// Dispatches events and loads changed selections until the connection breaks or the compositor
+/// invalidates our data device.
+fn run(mut queue: EventQueue<State>, mut state: State, conn: Connection) {
+    while !state.finished {
+        if let Err(e) = queue.blocking_dispatch(&mut state) {
+            println!("clipboard: wayland dispatch failed, stopping watcher: {}", e);
+            return;
+        }
+        state.load_pending(&conn, true);
+    }
+    println!("clipboard: data device finished, stopping watcher");
+}
+
+impl State {
+    /// Loads the offers that became a selection and, if [notify] is set, reports changed content to
+    /// subscribers.
+    fn load_pending(&mut self, conn: &Connection, notify: bool) {
+        for (selection, offer) in self.pending.drain(..).collect::<Vec<_>>() {
+            let mime_types = self.offers.remove(&offer.id()).unwrap_or(Vec::new());
+            if mime_types.iter().any(|m| m == MARKER_TARGET) {
+                // This is our own offer, i.e. the change came from set.
+                offer.destroy();
+                continue;
+            }
+
+            let content = load_offer(conn, &offer, &mime_types);
+            offer.destroy();
+            if content.is_empty() {
+                continue;
+            }
+
+            let mut shared = self.shared.lock().unwrap();
+            if shared.last_content.get(&selection) == Some(&content) {
+                continue;
+            }
+            shared.last_content.insert(selection, content.clone());
+            if notify {
+                // Drop subscribers whose receiver is gone.
+                shared
+                    .subscribers
+                    .retain(|s| s.send((selection, content.clone())).is_ok());
+            }
+        }
+    }
+}
+
+/// Reads every target of [offer] that we can name by MIME type.
+fn load_offer(
+    conn: &Connection,
+    offer: &ZwlrDataControlOfferV1,
+    mime_types: &[String],
+) -> ClipboardContent {
+    let mut content = ClipboardContent::new();
+    for mime_type in mime_types {
+        let target = if mime_type == TEXT_TARGET || mime_type == "UTF8_STRING" {
+            TEXT_TARGET.to_string()
+        } else if mime_type.contains('/') && !TEXT_ALIASES.contains(&mime_type.as_str()) {
+            mime_type.clone()
+        } else {
+            // Skip legacy names, we'll get the text under its proper MIME type.
+            continue;
+        };
+        if content.get(&target).is_some() {
+            continue;
+        }
+
+        match receive(conn, offer, mime_type) {
+            Ok(data) => content.insert(&target, data),
+            Err(e) => println!("clipboard: unable to load target {}: {}", mime_type, e),
+        }
+    }
+    content
+}
+
+/// Asks the source of [offer] to write [mime_type] into a pipe and reads it to the end.
+/// Fails if the source doesn't finish within `RECEIVE_TIMEOUT_MS`.
+fn receive(
+    conn: &Connection,
+    offer: &ZwlrDataControlOfferV1,
+    mime_type: &str,
+) -> Result<Vec<u8>, Box<Error>> {
+    let (mut reader, writer) = io::pipe()?;
+    offer.receive(mime_type.to_string(), writer.as_fd());
+    conn.flush()?;
+    // Close our end of the writer, otherwise we never see EOF.
+    drop(writer);
+
+    let deadline = Instant::now() + Duration::from_millis(RECEIVE_TIMEOUT_MS);
+    let mut data = Vec::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(From::from("source did not finish writing in time"));
+        }
+        let remaining = deadline - now;
+        let mut pollfd = libc::pollfd {
+            fd: reader.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = remaining.as_secs() * 1000 + u64::from(remaining.subsec_millis()) + 1;
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout as libc::c_int) };
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(From::from(e));
+        }
+        if ready == 0 {
+            continue;
+        }
+        // Readable or hung up, either way this doesn't block.
+        match reader.read(&mut buf)? {
+            0 => return Ok(data),
+            n => data.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+impl ClipboardBackend for WaylandClipboard {
+    fn get(&mut self, selection: Selection) -> Result<ClipboardContent, Box<Error>> {
+        // Every change is loaded eagerly by the event loop, so the last content is current.
+        let shared = self.shared.lock().unwrap();
+        Ok(shared
+            .last_content
+            .get(&selection)
+            .cloned()
+            .unwrap_or(ClipboardContent::new()))
+    }
+
+    /// Takes ownership of [selection] with a data source offering every target of [content].
+    fn set(&mut self, selection: Selection, content: ClipboardContent) -> Result<(), Box<Error>> {
+        if selection == Selection::Primary && self.device.version() < 2 {
+            return Err(From::from("compositor does not support the primary selection"));
+        }
+        {
+            let mut shared = self.shared.lock().unwrap();
+            if shared.last_content.get(&selection) == Some(&content) {
+                return Ok(());
+            }
+            shared.last_content.insert(selection, content.clone());
+        }
+
+        let source = self.manager
+            .create_data_source(&self.qh, Arc::new(content.clone()));
+        for target in content.targets() {
+            source.offer(target.clone());
+            if target == TEXT_TARGET {
+                for alias in TEXT_ALIASES {
+                    source.offer(alias.to_string());
+                }
+            }
+        }
+        source.offer(MARKER_TARGET.to_string());
+
+        match selection {
+            Selection::Clipboard => self.device.set_selection(Some(&source)),
+            Selection::Primary => self.device.set_primary_selection(Some(&source)),
+        }
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    fn subscribe(&mut self) -> Receiver<(Selection, ClipboardContent)> {
+        let (sendr, recvr) = channel();
+        self.shared.lock().unwrap().subscribers.push(sendr);
+        recvr
+    }
+}
+
+impl Dispatch<wl_registry::WlRegistry, ()> for State {
+    fn event(
+        state: &mut Self,
+        registry: &wl_registry::WlRegistry,
+        event: wl_registry::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let wl_registry::Event::Global {
+            name,
+            interface,
+            version,
+        } = event
+        {
+            if interface == "wl_seat" && state.seat.is_none() {
+                state.seat = Some(registry.bind(name, 1, qh, ()));
+            } else if interface == "zwlr_data_control_manager_v1" {
+                // Version 2 adds the primary selection.
+                state.manager = Some(registry.bind(name, version.min(2), qh, ()));
+            }
+        }
+    }
+}
+
+impl Dispatch<wl_seat::WlSeat, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &wl_seat::WlSeat,
+        _: wl_seat::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwlrDataControlManagerV1, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &ZwlrDataControlManagerV1,
+        _: <ZwlrDataControlManagerV1 as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwlrDataControlDeviceV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrDataControlDeviceV1,
+        event: zwlr_data_control_device_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_data_control_device_v1::Event::DataOffer { id } => {
+                state.offers.insert(id.id(), Vec::new());
+            }
+            zwlr_data_control_device_v1::Event::Selection { id: Some(offer) } => {
+                state.pending.push((Selection::Clipboard, offer));
+            }
+            zwlr_data_control_device_v1::Event::PrimarySelection { id: Some(offer) } => {
+                state.pending.push((Selection::Primary, offer));
+            }
+            zwlr_data_control_device_v1::Event::Finished => {
+                state.finished = true;
+            }
+            // The selection was cleared, there's nothing to report.
+            _ => (),
+        }
+    }
+
+    wayland_client::event_created_child!(State, ZwlrDataControlDeviceV1, [
+        zwlr_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ZwlrDataControlOfferV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrDataControlOfferV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        offer: &ZwlrDataControlOfferV1,
+        event: zwlr_data_control_offer_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zwlr_data_control_offer_v1::Event::Offer { mime_type } = event {
+            if let Some(mime_types) = state.offers.get_mut(&offer.id()) {
+                mime_types.push(mime_type);
+            }
+        }
+    }
+}
+
+impl Dispatch<ZwlrDataControlSourceV1, Arc<ClipboardContent>> for State {
+    fn event(
+        _: &mut Self,
+        source: &ZwlrDataControlSourceV1,
+        event: zwlr_data_control_source_v1::Event,
+        content: &Arc<ClipboardContent>,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_data_control_source_v1::Event::Send { mime_type, fd } => {
+                let target = if TEXT_ALIASES.contains(&mime_type.as_str()) {
+                    TEXT_TARGET.to_string()
+                } else {
+                    mime_type
+                };
+                let data = content
+                    .get(&target)
+                    .map(|data| data.to_vec())
+                    .unwrap_or(Vec::new());
+                // Write in the background, a slow reader must not block the event loop.
+                thread::spawn(move || {
+                    let mut file = File::from(fd);
+                    if let Err(e) = file.write_all(&data) {
+                        println!("clipboard: unable to send target {}: {}", target, e);
+                    }
+                });
+            }
+            zwlr_data_control_source_v1::Event::Cancelled => {
+                // Someone else owns the selection now.
+                source.destroy();
+            }
+            _ => (),
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn wayland_basics() {
+    // Needs a compositor supporting wlr-data-control, e.g. a headless Sway:
+    // WLR_BACKENDS=headless sway & WAYLAND_DISPLAY=wayland-1 cargo test -- --ignored
+    let mut cb = WaylandClipboard::init().unwrap();
+    let recv = cb.subscribe();
+
+    let mut data = ClipboardContent::from_text("HAHUHY");
+    data.insert("text/html", b"<b>HAHUHY</b>".to_vec());
+    cb.set(Selection::Clipboard, data.clone()).unwrap();
+    cb.set(Selection::Primary, data.clone()).unwrap();
+    assert_eq!(cb.get(Selection::Clipboard).unwrap(), data);
+    assert_eq!(cb.get(Selection::Primary).unwrap(), data);
+    // We set these ourselves, so they're not reported as a change.
+    assert!(recv.recv_timeout(::std::time::Duration::from_millis(500)).is_err());
+}
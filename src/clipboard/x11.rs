@@ -0,0 +1,350 @@
+extern crate x11_clipboard;
+extern crate xcb;
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+use self::x11_clipboard::Clipboard as X11CB;
+use self::xcb::xfixes;
+
+use super::{ClipboardBackend, ClipboardContent, Selection, DEFAULT_TARGETS, TEXT_TARGET};
+
+/// How long to wait for the selection owner to hand over its contents after it changed.
+const LOAD_TIMEOUT_SECS: u64 = 3;
+
+/// The content last seen or set for each selection, used to suppress duplicate change reports.
+type LastContent = Arc<Mutex<HashMap<Selection, ClipboardContent>>>;
+
+fn selection_atom(cb: &X11CB, selection: Selection) -> xcb::Atom {
+    match selection {
+        Selection::Clipboard => cb.getter.atoms.clipboard,
+        Selection::Primary => cb.getter.atoms.primary,
+    }
+}
+
+/// The receivers changes are reported to.
+type Subscribers = Arc<Mutex<Vec<Sender<(Selection, ClipboardContent)>>>>;
+
+/// An X11Clipboard is a ClipboardBackend for the CLIPBOARD and PRIMARY selections of an X server.
+pub struct X11Clipboard {
+    x11_clipboard: X11CB,
+    last_content: LastContent,
+    subscribers: Subscribers,
+}
+
+impl X11Clipboard {
+    /// Connects to the X server and starts watching the CLIPBOARD and PRIMARY selections for
+    /// changes.
+    /// Every time another application takes ownership of a selection, its new content is sent
+    /// to all subscribers.
+    pub fn init() -> Result<Self, Box<Error>> {
+        let cb = X11CB::new()?;
+        let last_content = Arc::new(Mutex::new(HashMap::new()));
+        let subscribers = Arc::new(Mutex::new(Vec::new()));
+
+        let watcher = Watcher::new(cb.setter.window, last_content.clone(), subscribers.clone())?;
+        thread::spawn(move || watcher.run());
+
+        let mut obj = X11Clipboard {
+            x11_clipboard: cb,
+            last_content: last_content,
+            subscribers: subscribers,
+        };
+
+        obj.set(Selection::Clipboard, ClipboardContent::new())?;
+        Ok(obj)
+    }
+}
+
+impl ClipboardBackend for X11Clipboard {
+    fn get(&mut self, selection: Selection) -> Result<ClipboardContent, Box<Error>> {
+        load_content(&self.x11_clipboard, selection)
+    }
+
+    /// Takes ownership of [selection] with [data].
+    /// x11_clipboard can only offer a single target per selection, so we offer the one we prefer
+    /// most out of [DEFAULT_TARGETS], or any target if none of those are present.
+    fn set(&mut self, selection: Selection, data: ClipboardContent) -> Result<(), Box<Error>> {
+        let target = match data.best_target(DEFAULT_TARGETS) {
+            Some(target) => target,
+            None => match data.targets().into_iter().next() {
+                Some(target) => target,
+                None => return Ok(()),
+            },
+        };
+        let mut stored = ClipboardContent::new();
+        stored.insert(&target, data.get(&target).unwrap().to_vec());
+
+        {
+            let mut last_content = self.last_content.lock().unwrap();
+            if last_content.get(&selection) == Some(&stored) {
+                return Ok(());
+            }
+            last_content.insert(selection, stored.clone());
+        }
+
+        let atom = if target == TEXT_TARGET {
+            self.x11_clipboard.setter.atoms.utf8_string
+        } else {
+            self.x11_clipboard.setter.get_atom(&target)?
+        };
+        Ok(self.x11_clipboard.store(
+            selection_atom(&self.x11_clipboard, selection),
+            atom,
+            stored.get(&target).unwrap(),
+        )?)
+    }
+
+    fn subscribe(&mut self) -> Receiver<(Selection, ClipboardContent)> {
+        let (sendr, recvr) = channel();
+        self.subscribers.lock().unwrap().push(sendr);
+        recvr
+    }
+}
+
+/// Loads [selection] in every target offered by its owner that we can name by MIME type.
+/// Owners that don't answer a TARGETS request are asked for text only.
+fn load_content(cb: &X11CB, selection: Selection) -> Result<ClipboardContent, Box<Error>> {
+    let timeout = Duration::from_secs(LOAD_TIMEOUT_SECS);
+    let atoms = match load_targets(cb, selection, timeout) {
+        Ok(atoms) => atoms,
+        Err(e) => {
+            println!("clipboard: unable to load targets, loading text only: {}", e);
+            vec![cb.getter.atoms.utf8_string]
+        }
+    };
+
+    let mut content = ClipboardContent::new();
+    for atom in atoms {
+        let target = if atom == cb.getter.atoms.utf8_string {
+            TEXT_TARGET.to_string()
+        } else {
+            let reply = xcb::get_atom_name(&cb.getter.connection, atom).get_reply()?;
+            // Skip meta targets (TARGETS, TIMESTAMP, ...) and legacy encodings.
+            if !reply.name().contains('/') {
+                continue;
+            }
+            reply.name().to_string()
+        };
+        if content.get(&target).is_some() {
+            continue;
+        }
+
+        match cb.load(
+            selection_atom(cb, selection),
+            atom,
+            cb.getter.atoms.property,
+            timeout,
+        ) {
+            Ok(data) => content.insert(&target, data),
+            Err(e) => println!("clipboard: unable to load target {}: {}", target, e),
+        }
+    }
+
+    Ok(content)
+}
+
+/// Asks the owner of [selection] which targets it can convert the selection to.
+/// This can't go through `X11CB::load`, because the reply to TARGETS has type ATOM rather than
+/// TARGETS.
+fn load_targets(
+    cb: &X11CB,
+    selection: Selection,
+    timeout: Duration,
+) -> Result<Vec<xcb::Atom>, Box<Error>> {
+    let conn = &cb.getter.connection;
+    let property = cb.getter.atoms.property;
+    xcb::convert_selection(
+        conn,
+        cb.getter.window,
+        selection_atom(cb, selection),
+        cb.getter.atoms.targets,
+        property,
+        xcb::CURRENT_TIME,
+    );
+    conn.flush();
+
+    let start = Instant::now();
+    loop {
+        if start.elapsed() >= timeout {
+            return Err(From::from("timed out waiting for TARGETS"));
+        }
+
+        let event = match conn.poll_for_event() {
+            Some(event) => event,
+            None => {
+                thread::park_timeout(Duration::from_millis(50));
+                continue;
+            }
+        };
+        if event.response_type() & !0x80 != xcb::SELECTION_NOTIFY {
+            continue;
+        }
+        let event: &xcb::SelectionNotifyEvent = unsafe { xcb::cast_event(&event) };
+        if event.selection() != selection_atom(cb, selection) {
+            continue;
+        }
+        if event.property() == xcb::NONE {
+            return Err(From::from("owner refused to convert to TARGETS"));
+        }
+
+        let reply = xcb::get_property(
+            conn,
+            true,
+            cb.getter.window,
+            property,
+            xcb::ATOM_ATOM,
+            0,
+            ::std::u32::MAX,
+        ).get_reply()?;
+        return Ok(reply.value::<xcb::Atom>().to_vec());
+    }
+}
+
+/// A Watcher subscribes to XFixes selection owner change events for CLIPBOARD and PRIMARY and
+/// loads the new content whenever an owner changes.
+/// It uses its own connection for events and its own x11_clipboard instance for loading, so it
+/// never competes with the owning `X11Clipboard` for events.
+struct Watcher {
+    events: xcb::Connection,
+    loader: X11CB,
+    own_window: xcb::Window,
+    first_event: u8,
+    last_content: LastContent,
+    subscribers: Subscribers,
+}
+
+impl Watcher {
+    fn new(
+        own_window: xcb::Window,
+        last_content: LastContent,
+        subscribers: Subscribers,
+    ) -> Result<Watcher, Box<Error>> {
+        let (conn, screen) = xcb::Connection::connect(None)?;
+
+        let first_event = match conn.get_extension_data(xfixes::id()) {
+            Some(ref data) if data.present() => data.first_event(),
+            _ => return Err(From::from("X server does not support XFixes")),
+        };
+        // XFixes requires a version handshake before any other request.
+        xfixes::query_version(&conn, 1, 0).get_reply()?;
+
+        let root = conn.get_setup()
+            .roots()
+            .nth(screen as usize)
+            .ok_or("unable to find root window")?
+            .root();
+        let loader = X11CB::new()?;
+        for selection in &[Selection::Clipboard, Selection::Primary] {
+            xfixes::select_selection_input(
+                &conn,
+                root,
+                selection_atom(&loader, *selection),
+                xfixes::SELECTION_EVENT_MASK_SET_SELECTION_OWNER
+                    | xfixes::SELECTION_EVENT_MASK_SELECTION_WINDOW_DESTROY
+                    | xfixes::SELECTION_EVENT_MASK_SELECTION_CLIENT_CLOSE,
+            );
+        }
+        conn.flush();
+
+        Ok(Watcher {
+            events: conn,
+            loader: loader,
+            own_window: own_window,
+            first_event: first_event,
+            last_content: last_content,
+            subscribers: subscribers,
+        })
+    }
+
+    /// Blocks on the event connection and reports changed content until the X connection is
+    /// closed.
+    fn run(self) {
+        while let Some(event) = self.events.wait_for_event() {
+            if event.response_type() & !0x80 != self.first_event + xfixes::SELECTION_NOTIFY {
+                continue;
+            }
+            let event: &xfixes::SelectionNotifyEvent = unsafe { xcb::cast_event(&event) };
+
+            // We caused this change ourselves via set, or nobody owns the selection.
+            if event.owner() == self.own_window || event.owner() == xcb::NONE {
+                continue;
+            }
+            let selection = if event.selection() == self.loader.getter.atoms.primary {
+                Selection::Primary
+            } else {
+                Selection::Clipboard
+            };
+
+            let content = match load_content(&self.loader, selection) {
+                Ok(content) => content,
+                Err(e) => {
+                    println!("clipboard: unable to load changed {:?}: {}", selection, e);
+                    continue;
+                }
+            };
+
+            if content.is_empty() {
+                continue;
+            }
+            {
+                let mut last_content = self.last_content.lock().unwrap();
+                if last_content.get(&selection) == Some(&content) {
+                    continue;
+                }
+                last_content.insert(selection, content.clone());
+            }
+
+            // Drop subscribers whose receiver is gone.
+            self.subscribers
+                .lock()
+                .unwrap()
+                .retain(|s| s.send((selection, content.clone())).is_ok());
+        }
+        println!("clipboard: X connection closed, stopping watcher");
+    }
+}
+
+#[test]
+fn basics() {
+    let mut cb = X11Clipboard::init().unwrap();
+    let recv = cb.subscribe();
+
+    println!("!-----------------------!");
+    let stuff = cb.get(Selection::Clipboard).unwrap();
+    format!("{:?}\n", stuff);
+    println!("!-----------------------!");
+
+    let data = ClipboardContent::from_text("HAHUHY");
+    cb.set(Selection::Clipboard, data.clone()).unwrap();
+    cb.set(Selection::Primary, data.clone()).unwrap();
+    assert_eq!(cb.get(Selection::Clipboard).unwrap(), data);
+    assert_eq!(cb.get(Selection::Primary).unwrap(), data);
+    // We set these ourselves, so they're not reported as a change.
+    assert!(recv.recv_timeout(Duration::from_millis(500)).is_err());
+
+    //    let data = format!("{:?}", Instant::now());
+    //    let clipboard = Clipboard::new().unwrap();
+    //
+    //    let atom_clipboard = clipboard.setter.atoms.clipboard;
+    //    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    //    let atom_property = clipboard.setter.atoms.property;
+    //
+    //    clipboard.store(atom_clipboard, atom_utf8string, data.as_bytes()).unwrap();
+    //
+    //    let output = clipboard.load(atom_clipboard, atom_utf8string, atom_property, None).unwrap();
+    //    assert_eq!(output, data.as_bytes());
+    //
+    //    let data = format!("{:?}", Instant::now());
+    //    clipboard.store(atom_clipboard, atom_utf8string, data.as_bytes()).unwrap();
+    //
+    //    let output = clipboard.load(atom_clipboard, atom_utf8string, atom_property, None).unwrap();
+    //    assert_eq!(output, data.as_bytes());
+    //
+    //    let output = clipboard.load(atom_clipboard, atom_utf8string, atom_property, None).unwrap();
+    //    assert_eq!(output, data.as_bytes());
+}
+
@@ -1,8 +1,10 @@
 use std::hash::Hash;
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
+use std::cmp;
 use std::cmp::Ordering;
 use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 // This is the vector clock implementation taken from vectorclock-rs.
 // Some things are cleaned up, some things were added, some removed.
@@ -13,7 +15,7 @@ use std::fmt::Debug;
 /// other, and EffectOf means "this clock is an effect of the other clock", i.e. this clock came
 /// after the other.
 /// ConcurrentGreater and ConcurrentSmaller are relations necessary to resolve conflicts between
-/// concurrent clocks.
+/// concurrent clocks, they follow the total order of `VectorClock::total_cmp`.
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
 pub enum TemporalRelation {
     Equal,
@@ -24,9 +26,14 @@ pub enum TemporalRelation {
 }
 
 /// A VectorClock implements the vector clock algorithm.
+/// To keep it from growing with every host that ever incremented it, the entries of hosts that
+/// are gone can be folded into a base counter, which is treated like the entry of one more host.
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct VectorClock<HostType: Hash + Eq + Clone + Ord + Debug> {
     entries: HashMap<HostType, u64>,
+    /// The events of all hosts whose entries were folded.
+    #[serde(default)]
+    base: u64,
 }
 
 impl<HostType: Clone + Hash + Eq + Ord + Debug> VectorClock<HostType> {
@@ -34,6 +41,7 @@ impl<HostType: Clone + Hash + Eq + Ord + Debug> VectorClock<HostType> {
     pub fn new() -> VectorClock<HostType> {
         VectorClock {
             entries: HashMap::new(),
+            base: 0,
         }
     }
 
@@ -51,94 +59,90 @@ impl<HostType: Clone + Hash + Eq + Ord + Debug> VectorClock<HostType> {
             let mut count = entries.entry(host).or_insert(0);
             *count += 1;
         }
-        VectorClock { entries }
+        VectorClock {
+            entries: entries,
+            base: self.base,
+        }
     }
 
     /// Determines the temporal relation between this clock and [other].
     /// See the documentation on the `TemporalRelation` enum for an explanation of the values
     /// returned.
     pub fn temporal_relation(&self, other: &Self) -> TemporalRelation {
-        if self == other {
-            TemporalRelation::Equal
-        } else if self.superseded_by(other) {
+        if self.superseded_by(other) {
             // self caused other (smaller than other)
             TemporalRelation::Caused
         } else if other.superseded_by(self) {
             // self is an effect of other (larger than other)
             TemporalRelation::EffectOf
         } else {
-            if self.is_greater_concurrent_than(other) {
-                // self and other are concurrent, but we can order self to be greater than other
-                TemporalRelation::ConcurrentGreater
-            } else {
-                // self and other are concurrent, but we can order self to be smaller than other
-                TemporalRelation::ConcurrentSmaller
+            // self and other are equal or concurrent, but we can order them anyway
+            match self.total_cmp(other) {
+                Ordering::Equal => TemporalRelation::Equal,
+                Ordering::Greater => TemporalRelation::ConcurrentGreater,
+                Ordering::Less => TemporalRelation::ConcurrentSmaller,
             }
         }
     }
 
-    fn is_greater_concurrent_than(&self, other: &Self) -> bool {
-        let mut own_keys: Vec<HostType> = Vec::new();
-        let mut other_keys: Vec<HostType> = Vec::new();
-
-        {
-            for key in self.entries.keys() {
-                own_keys.push(key.clone());
-            }
-            for key in other.entries.keys() {
-                other_keys.push(key.clone());
-            }
-        }
-
-        own_keys.as_mut_slice().sort();
-        other_keys.as_mut_slice().sort();
-
-        // Comparing vectors also compares their length, but only after comparing each element.
-        // We move comparing the length before comparing the elements to avoid situations like:
-        // - clock 1: [[B:1],[C:2],[D:1],[A:2]]
-        // - clock 2: [[B:1],[C:2],[E:1]]
-        // Using just vector comparison would order clock 1 < clock 2 (because the vectors are
-        // sorted and A<B). Although these two are really concurrent and we can't enforce an order,
-        // it looks like clock 1 should be ordered "greater than" clock 2, just because it captured
-        // more events. This is arbitrary, one could decide not to follow this approach, but it felt
-        // more natural to us.
-        if own_keys.len() > other_keys.len() {
-            return true;
-        } else if other_keys.len() > own_keys.len() {
-            return false;
-        }
+    /// Orders this clock and [other] totally, in a way that is consistent with causality: a clock
+    /// that caused another one is smaller than it.
+    /// Clocks are ordered by the number of events they captured, i.e. the sum of their counters,
+    /// and clocks that captured the same number of events by their base counters, then by their
+    /// entries, sorted by host and compared lexicographically.
+    /// As for causality, counters of zero are the same as no entry, so this only returns Equal
+    /// for clocks that captured exactly the same events. Every node orders two clocks the same
+    /// way, no matter in which order it sees them.
+    pub fn total_cmp(&self, other: &Self) -> Ordering {
+        self.events()
+            .cmp(&other.events())
+            .then_with(|| self.base.cmp(&other.base))
+            .then_with(|| self.sorted_entries().cmp(&other.sorted_entries()))
+    }
 
-        let ord = own_keys.cmp(&other_keys);
+    /// Returns the number of events captured by this clock.
+    pub fn events(&self) -> u64 {
+        self.entries
+            .values()
+            .fold(self.base, |sum, &n| sum.saturating_add(n))
+    }
 
-        match ord {
-            Ordering::Less => false,
-            Ordering::Greater => true,
-            Ordering::Equal => {
-                // they have the same keys, just values are different.
-                println!(
-                    "clock: vector resolution failed: self clock: {:?}, other clock: {:?}",
-                    self, other
-                );
+    /// Returns the number of hosts this clock has an entry for.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
 
-                for i in 0..own_keys.len() {
-                    let own_val = self.entries.get(&own_keys[i]).unwrap();
-                    let other_val = other.entries.get(&own_keys[i]).unwrap();
-                    if own_val == other_val {
-                        continue;
-                    } else if own_val > other_val {
-                        return true;
-                    } else if own_val < other_val {
-                        return false;
-                    }
-                }
-
-                panic!("unable to resolve concurrent clocks");
-            }
+    /// Folds the entries of all hosts for which [keep] returns false into the base counter.
+    /// This captures the same number of events in a larger base counter, so by `total_cmp` the
+    /// folded clock is greater than or equal to this one, and greater than every clock smaller
+    /// than this one. Hosts that are folded but still around start over at zero when they
+    /// increment the clock next, which again makes it greater.
+    /// Causal relations to clocks that still have the folded entries are lost, they become
+    /// concurrent.
+    pub fn fold<F: Fn(&HostType) -> bool>(&mut self, keep: F) {
+        let folded: Vec<HostType> = self.entries.keys().filter(|h| !keep(h)).cloned().collect();
+        for host in folded {
+            let n = self.entries.remove(&host).unwrap();
+            self.base = self.base.saturating_add(n);
         }
     }
 
+    /// Returns the entries with a counter greater than zero, sorted by host.
+    fn sorted_entries(&self) -> Vec<(&HostType, u64)> {
+        let mut entries: Vec<(&HostType, u64)> = self.entries
+            .iter()
+            .filter(|&(_, &n)| n > 0)
+            .map(|(host, &n)| (host, n))
+            .collect();
+        entries.sort();
+        entries
+    }
+
     fn superseded_by(&self, other: &Self) -> bool {
-        let mut has_smaller = false;
+        if self.base > other.base {
+            return false;
+        }
+        let mut has_smaller = self.base < other.base;
 
         for (host, &self_n) in self.entries.iter() {
             let other_n = *other.entries.get(host).unwrap_or(&0);
@@ -175,16 +179,103 @@ impl<HostType: Clone + Hash + Eq + Ord + Debug> VectorClock<HostType> {
             }
         }
 
-        VectorClock { entries }
+        VectorClock {
+            entries: entries,
+            base: cmp::max(self.base, other.base),
+        }
+    }
+}
+
+/// A HybridTimestamp is a timestamp of a HybridClock: the wall-clock time in milliseconds since
+/// the Unix epoch, or a bit later, and a counter that orders events within the same millisecond.
+/// Timestamps are ordered by time, then by counter.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct HybridTimestamp {
+    pub time: u64,
+    pub counter: u32,
+}
+
+/// A HybridClock implements a hybrid logical clock: its timestamps follow the wall-clock time,
+/// but are also greater than every timestamp the clock produced or observed before, even if the
+/// wall-clock of another node is ahead of ours or ours goes backwards.
+/// This way, an event that happened after another one, as far as we know, always gets a greater
+/// timestamp, and concurrent events are ordered roughly by the time they happened.
+#[derive(Debug, Clone, Default)]
+pub struct HybridClock {
+    latest: HybridTimestamp,
+}
+
+impl HybridClock {
+    /// Creates a HybridClock that has not produced any timestamps yet.
+    pub fn new() -> HybridClock {
+        HybridClock::default()
+    }
+
+    /// Returns a timestamp for a local event that happened after the remote event with the
+    /// timestamp [remote].
+    pub fn observe(&mut self, remote: HybridTimestamp) -> HybridTimestamp {
+        self.tick(wall_clock_millis(), remote)
+    }
+
+    /// Returns a timestamp greater than [remote] and all timestamps before, at the wall-clock
+    /// time [wall].
+    fn tick(&mut self, wall: u64, remote: HybridTimestamp) -> HybridTimestamp {
+        let latest = cmp::max(self.latest, remote);
+        self.latest = if wall > latest.time {
+            HybridTimestamp {
+                time: wall,
+                counter: 0,
+            }
+        } else if latest.counter < u32::max_value() {
+            HybridTimestamp {
+                time: latest.time,
+                counter: latest.counter + 1,
+            }
+        } else {
+            // Billions of events within a millisecond, move on to the next one.
+            HybridTimestamp {
+                time: latest.time.saturating_add(1),
+                counter: 0,
+            }
+        };
+        self.latest
+    }
+}
+
+/// Returns the wall-clock time in milliseconds since the Unix epoch, or zero if the clock is set
+/// to before that.
+fn wall_clock_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1000 + u64::from(d.subsec_nanos() / 1_000_000),
+        Err(_) => 0,
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{TemporalRelation, VectorClock};
+    use std::cmp::Ordering;
+    use std::collections::HashMap;
+
+    use proptest::prelude::*;
+
+    use super::{HybridClock, HybridTimestamp, TemporalRelation, VectorClock};
 
     type StrVectorClock = VectorClock<&'static str>;
 
+    /// Generates clocks over a few hosts, so that generated clocks are often related, some of
+    /// them with entries folded into their base counters.
+    fn arb_clock() -> BoxedStrategy<StrVectorClock> {
+        let hosts = prop::sample::select(vec!["A", "B", "C", "D"]);
+        (
+            prop::collection::hash_map(hosts, 0u64..4, 0..4),
+            prop_oneof![Just(0u64), 0u64..6],
+        ).prop_map(|(entries, base): (HashMap<&'static str, u64>, u64)| VectorClock {
+                entries: entries,
+                base: base,
+            })
+            .boxed()
+    }
+
     #[test]
     fn test_empty_ordering() {
         let c1 = StrVectorClock::new();
@@ -261,4 +352,151 @@ mod test {
         assert_eq!(m.temporal_relation(&c2), TemporalRelation::EffectOf);
         assert_eq!(c2.temporal_relation(&m), TemporalRelation::Caused);
     }
+
+    #[test]
+    fn test_more_events_concurrent() {
+        // c1 captured more events, c2 has more hosts.
+        let base = StrVectorClock::new();
+        let c1 = base.incr_clone("A").incr_clone("A").incr_clone("A");
+        let c2 = base.incr_clone("B").incr_clone("C");
+
+        assert_eq!(
+            c1.temporal_relation(&c2),
+            TemporalRelation::ConcurrentGreater
+        );
+        assert_eq!(
+            c2.temporal_relation(&c1),
+            TemporalRelation::ConcurrentSmaller
+        );
+    }
+
+    #[test]
+    fn test_zero_entries_ignored() {
+        let mut c1 = StrVectorClock::new();
+        c1.entries.insert("A", 0);
+        let c2 = StrVectorClock::new();
+
+        assert_eq!(c1.temporal_relation(&c2), TemporalRelation::Equal);
+        assert_eq!(c1.total_cmp(&c2), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_folded() {
+        let base = StrVectorClock::new();
+        let c1 = base.incr_clone("A").incr_clone("B");
+        let mut folded = c1.clone();
+        folded.fold(|h| *h == "B");
+        assert_eq!(folded.len(), 1);
+        assert_eq!(folded.total_cmp(&c1), Ordering::Greater);
+
+        // A is gone for good, B copies next.
+        let c2 = folded.incr_clone("B");
+        assert_eq!(folded.temporal_relation(&c2), TemporalRelation::Caused);
+        assert_eq!(c1.total_cmp(&c2), Ordering::Less);
+        // A was wrongly folded and copies again.
+        let c3 = folded.incr_clone("A");
+        assert_eq!(c1.total_cmp(&c3), Ordering::Less);
+        assert_eq!(folded.temporal_relation(&c3), TemporalRelation::Caused);
+    }
+
+    #[test]
+    fn test_hybrid_clock_follows_wall_clock() {
+        let mut hlc = HybridClock::new();
+        let t1 = hlc.tick(1000, HybridTimestamp::default());
+        assert_eq!(t1, HybridTimestamp { time: 1000, counter: 0 });
+        // The wall-clock went backwards.
+        let t2 = hlc.tick(900, HybridTimestamp::default());
+        assert_eq!(t2, HybridTimestamp { time: 1000, counter: 1 });
+        let t3 = hlc.tick(1100, HybridTimestamp::default());
+        assert_eq!(t3, HybridTimestamp { time: 1100, counter: 0 });
+    }
+
+    #[test]
+    fn test_hybrid_clock_observes_remote() {
+        let mut hlc = HybridClock::new();
+        hlc.tick(1000, HybridTimestamp::default());
+        // The remote wall-clock is ahead of ours.
+        let remote = HybridTimestamp { time: 2000, counter: 3 };
+        let t = hlc.tick(1001, remote);
+        assert_eq!(t, HybridTimestamp { time: 2000, counter: 4 });
+        assert!(hlc.tick(1002, HybridTimestamp::default()) > t);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_total_order_antisymmetric(c1 in arb_clock(), c2 in arb_clock()) {
+            prop_assert_eq!(c1.total_cmp(&c2), c2.total_cmp(&c1).reverse());
+            let (r1, r2) = (c1.temporal_relation(&c2), c2.temporal_relation(&c1));
+            let mirrored = match r1 {
+                TemporalRelation::Equal => TemporalRelation::Equal,
+                TemporalRelation::Caused => TemporalRelation::EffectOf,
+                TemporalRelation::EffectOf => TemporalRelation::Caused,
+                TemporalRelation::ConcurrentGreater => TemporalRelation::ConcurrentSmaller,
+                TemporalRelation::ConcurrentSmaller => TemporalRelation::ConcurrentGreater,
+            };
+            prop_assert_eq!(r2, mirrored);
+        }
+
+        #[test]
+        fn prop_total_order_transitive(c1 in arb_clock(), c2 in arb_clock(), c3 in arb_clock()) {
+            let mut clocks = vec![c1, c2, c3];
+            clocks.sort_by(|a, b| a.total_cmp(b));
+            prop_assert_ne!(clocks[0].total_cmp(&clocks[1]), Ordering::Greater);
+            prop_assert_ne!(clocks[1].total_cmp(&clocks[2]), Ordering::Greater);
+            prop_assert_ne!(clocks[0].total_cmp(&clocks[2]), Ordering::Greater);
+            if clocks[0].total_cmp(&clocks[1]) == Ordering::Less
+                || clocks[1].total_cmp(&clocks[2]) == Ordering::Less
+            {
+                prop_assert_eq!(clocks[0].total_cmp(&clocks[2]), Ordering::Less);
+            }
+        }
+
+        #[test]
+        fn prop_total_order_respects_causality(c1 in arb_clock(), c2 in arb_clock()) {
+            let merged = c1.merge_with(&c2);
+            for c in &[&c1, &c2] {
+                let relation = c.temporal_relation(&merged);
+                prop_assert!(
+                    relation == TemporalRelation::Caused || relation == TemporalRelation::Equal
+                );
+            }
+            prop_assert_ne!(c1.total_cmp(&merged), Ordering::Greater);
+            if c1.temporal_relation(&merged) == TemporalRelation::Caused {
+                prop_assert_eq!(c1.total_cmp(&merged), Ordering::Less);
+            }
+            match c1.temporal_relation(&c2) {
+                TemporalRelation::Caused => prop_assert_eq!(c1.total_cmp(&c2), Ordering::Less),
+                TemporalRelation::EffectOf => prop_assert_eq!(c1.total_cmp(&c2), Ordering::Greater),
+                _ => (),
+            }
+        }
+
+        #[test]
+        fn prop_folding_keeps_order(c1 in arb_clock(), c2 in arb_clock(), host in prop::sample::select(vec!["A", "B", "C", "D"])) {
+            let mut folded = c1.clone();
+            folded.fold(|h| *h != host);
+            prop_assert_ne!(folded.total_cmp(&c1), Ordering::Less);
+            if c2.total_cmp(&c1) == Ordering::Less {
+                prop_assert_eq!(c2.total_cmp(&folded), Ordering::Less);
+            }
+            // Incrementing a folded clock makes it greater than everything before.
+            let next = folded.incr_clone(host);
+            prop_assert_eq!(c1.total_cmp(&next), Ordering::Less);
+            prop_assert_eq!(folded.temporal_relation(&next), TemporalRelation::Caused);
+        }
+
+        #[test]
+        fn prop_hybrid_clock_is_monotonic(events in prop::collection::vec((0u64..10, 0u64..10, 0u32..3), 1..20)) {
+            let mut hlc = HybridClock::new();
+            let mut last = HybridTimestamp::default();
+            for (wall, remote_time, remote_counter) in events {
+                let remote = HybridTimestamp { time: remote_time, counter: remote_counter };
+                let t = hlc.tick(wall, remote);
+                prop_assert!(t > last);
+                prop_assert!(t > remote);
+                prop_assert!(t.time >= wall);
+                last = t;
+            }
+        }
+    }
 }
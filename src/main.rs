@@ -1,16 +1,29 @@
 #![feature(mpsc_select)]
 
+extern crate bincode;
+extern crate blake2;
 extern crate byteorder;
+extern crate flate2;
+extern crate hex;
+extern crate net2;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
 extern crate rand;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate signal_hook;
+extern crate snow;
 
 mod clock;
 mod network;
 mod overlay;
 mod clipboard;
+mod secure;
+mod seen;
+mod sync;
 
 use clock::*;
 use network::*;
@@ -19,17 +32,122 @@ use overlay::*;
 use std::sync::Arc;
 use std::net::*;
 use std::env;
+use std::io::{self, Read, Write};
+use std::process;
 use std::thread;
-use std::time;
-use clipboard::Clipboard;
-use std::sync::Mutex;
-use rand::Rng;
+use clipboard::{headless, Selection, SyncMode, TEXT_TARGET};
 use std::str::FromStr;
+use std::fmt;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+/// Prints the given problem with the commandline arguments and exits.
+fn usage(problem: &str) -> ! {
+    eprintln!("{}", problem);
+    eprintln!(
+        "usage: paste-anywhere [--sync=<mode>] [--codec=<codec>] [--compression=<compression>] \
+         [--max-message-size=<bytes>] [--push-size=<size>] [--degree=<min>-<max>] \
+         [--port=<port>] [--conflicts=<policy>] [--headless] <local-ip> [<bootstrap-peer>...]"
+    );
+    process::exit(2);
+}
+
+/// Parses the value of the flag [arg] that starts with [prefix], exiting with the usage if it is
+/// invalid.
+fn parse_flag<T: FromStr>(arg: &str, prefix: &str) -> T
+where
+    T::Err: fmt::Display,
+{
+    match arg[prefix.len()..].parse() {
+        Ok(value) => value,
+        Err(e) => usage(&format!("invalid value in {}: {}", arg, e)),
+    }
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let (cb,_)=Clipboard::init().unwrap();
-    let c= Arc::new(Mutex::new(cb));
+    let mut args: Vec<String> = Vec::new();
+    let mut sync_mode = SyncMode::default();
+    let mut headless = false;
+    let mut selection = Selection::Clipboard;
+    let mut target = TEXT_TARGET.to_string();
+    let mut codecs = None;
+    let mut max_message_size = None;
+    let mut push_size = None;
+    let mut conflict_policy = None;
+    let mut compression = None;
+    let mut degree = None;
+    let mut port = 0;
+    for arg in env::args() {
+        if arg.starts_with("--sync=") {
+            sync_mode = parse_flag(&arg, "--sync=");
+        } else if arg.starts_with("--codec=") {
+            codecs = Some(vec![parse_flag(&arg, "--codec=")]);
+        } else if arg.starts_with("--degree=") {
+            let bounds: Result<Vec<usize>, _> = arg["--degree=".len()..]
+                .split('-')
+                .map(|d| d.parse())
+                .collect();
+            degree = match bounds {
+                Ok(ref bounds) if bounds.len() <= 2 => Some((bounds[0], *bounds.last().unwrap())),
+                _ => usage(&format!("invalid degree in {}, expected <min>-<max>", arg)),
+            };
+        } else if arg.starts_with("--compression=") {
+            compression = Some(vec![parse_flag(&arg, "--compression=")]);
+        } else if arg.starts_with("--max-message-size=") {
+            max_message_size = Some(parse_flag(&arg, "--max-message-size="));
+        } else if arg.starts_with("--push-size=") {
+            push_size = Some(parse_flag(&arg, "--push-size="));
+        } else if arg.starts_with("--port=") {
+            port = parse_flag(&arg, "--port=");
+        } else if arg.starts_with("--conflicts=") {
+            conflict_policy = Some(parse_flag(&arg, "--conflicts="));
+        } else if arg == "--headless" {
+            headless = true;
+        } else if arg == "--primary" {
+            selection = Selection::Primary;
+        } else if arg.starts_with("--target=") {
+            target = arg["--target=".len()..].to_string();
+        } else {
+            args.push(arg);
+        }
+    }
+
+    // copy and paste talk to a headless node running on this machine.
+    match args.get(1).map(|a| a.as_str()) {
+        Some("copy") => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data).unwrap();
+            let resp = headless::socket_path()
+                .and_then(|path| headless::copy(&path, selection, &target, &data));
+            if let Err(e) = resp {
+                eprintln!("copy failed: {}", e);
+                process::exit(1);
+            }
+            return;
+        }
+        Some("paste") => match headless::socket_path()
+            .and_then(|path| headless::paste(&path, selection, &target))
+        {
+            Ok(data) => {
+                io::stdout().write_all(&data).unwrap();
+                return;
+            }
+            Err(e) => {
+                eprintln!("paste failed: {}", e);
+                process::exit(1);
+            }
+        },
+        _ => (),
+    }
+
+    let local_ip = match args.get(1) {
+        Some(arg) => IpAddr::from_str(arg).unwrap_or(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))),
+        None => usage("no local IP given"),
+    };
+
+    println!("syncing selections as {:?}", sync_mode);
+
+    let cb = clipboard::init(headless).unwrap();
 
 //    if args[1].starts_with("clip") {
 //        let mut cbi = Clipboard::init().unwrap();
@@ -46,21 +164,58 @@ fn main() {
 //        print!("{:?}\n", x);
 //        return;
 //    }
-    let local_ip=Ipv4Addr::from_str(args[1].as_ref())
-        .unwrap_or(Ipv4Addr::new(127, 0, 0, 1));
     let mut bootstrap_peers: Vec<Endpoint> = Vec::new();
     for i in 2..args.len() {
         let p = &args[i];
-        let addr: SocketAddr = p.parse().unwrap();
-        if !addr.is_ipv4() {
-            panic!("address is not IPv4")
-        }
-        if let SocketAddr::V4(v4) = addr {
-            bootstrap_peers.push(Endpoint::new(v4.ip(), v4.port()));
+        // Hostnames may resolve to several addresses, try all of them.
+        match p.to_socket_addrs() {
+            Ok(addrs) => for addr in addrs {
+                bootstrap_peers.push(Endpoint::from(addr));
+            },
+            Err(e) => println!("unable to resolve bootstrap peer {}: {}", p, e),
         }
     }
 
-    let o = Arc::new(Overlay::new(&local_ip, bootstrap_peers).unwrap());
+    let mut config = ConnectionConfig::new(secure::Keys::load().unwrap());
+    if let Some(codecs) = codecs {
+        config.codecs = codecs;
+    }
+    if let Some(compression) = compression {
+        config.compression = compression;
+    }
+    if let Some(max_message_size) = max_message_size {
+        config.max_message_size = max_message_size;
+    }
+    if let Some(push_size) = push_size {
+        config.push_size = push_size;
+    }
+    if config.max_push_size() < config.push_size {
+        println!(
+            "pushing at most {} bytes, larger notifications would exceed the maximum message size",
+            config.max_push_size()
+        );
+    }
+    if let Some(conflict_policy) = conflict_policy {
+        config.conflict_policy = conflict_policy;
+    }
+    let mut o = Overlay::new(&local_ip, port, bootstrap_peers, config).unwrap();
+    if let Some((min, max)) = degree {
+        o.set_degree(min, max);
+    }
+    let o = Arc::new(o);
+
+    // Leave the overlay cleanly when interrupted or terminated.
+    let mut signals = Signals::new(&[SIGINT, SIGTERM]).unwrap();
+    {
+        let o = o.clone();
+        thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                println!("received signal {}, shutting down", signal);
+                o.shutdown();
+                process::exit(0);
+            }
+        });
+    }
 
     o.start_accepting();
     o.start_autoping();
@@ -70,45 +225,11 @@ fn main() {
     if let Err(e) = join {
         println!("join failed: {}", e);
     }
+    Overlay::start_maintenance(o.clone());
 
-    {
-        let oo = o.clone();
-        let cc = c.clone();
-        thread::spawn(move || {
-            loop {
-                thread::sleep(time::Duration::new(0, 197 * 1000 * 1000));
-                //println!("getting clipboard...");
-                let resp = oo.get_clipboard();
-                match resp {
-                    Err(e) => println!("unable to get clipboard: {}", e),
-                    Ok(Some(content)) => {
-                        //println!("clipboard is: {}", content.clone());
-                        cc.lock().unwrap().set_contents(content);
-                    },
-                    //Ok(None) => println!("clipboard is local!"),
-                    Ok(None) => (),
-                };
-            }
-            println!("-oo-supervisor-thread-closed-");
-        });
-    }
-    let mut rng=rand::thread_rng();
-    loop {
-        let cb_content=c.lock().unwrap().get_contents();
-        match cb_content{
-            Err(e) => println!("\tunable to get local clipboard: {}", e),
-            Ok(Some(content)) => {
-                println!("\tlocal clipboard is: {}", content.clone());
-                o.set_clipboard(content.clone().as_ref());
-            },
-//            Ok(None) => println!("\tclipboard has not changed!"),
-            Ok(None) => (),
-        }
-        //sleep 200ms + random?
-//        let ns=rng.gen_range();
-        thread::sleep(time::Duration::new(0,211*1000*1000))
-    }
-
+    sync::run(o.clone(), cb, sync_mode);
+    println!("clipboard watcher stopped, exiting");
+    o.shutdown();
 
 //    //TESTING
 //    for i in 0..100 {
@@ -2,27 +2,80 @@ use std::net;
 use std::error::Error;
 use std::io::Read;
 use std::io;
-use std::net::TcpStream;
 use std::net::SocketAddr;
 use std::io::Write;
 use std::str::FromStr;
+use std::fmt;
+use std::cmp;
+use std::time;
 
 use rand;
 use rand::Rng;
 use serde;
 use serde_json;
+use bincode;
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2;
+use hex;
+use blake2::{Blake2s256, Digest};
+
+use clock::{HybridTimestamp, VectorClock};
+use clipboard::{ClipboardContent, Selection};
+use secure::{Keys, SecureStream};
+
+/// A PeerID is the static public key a peer authenticates with.
+/// It is persisted on disk, so unlike the endpoint of a peer it survives restarts.
+/// It serializes to a hex string so it can be used as a key for JSON maps.
+#[derive(Ord, PartialOrd, PartialEq, Eq, Hash, Copy, Clone)]
+pub struct PeerID(pub [u8; 32]);
+
+impl PeerID {
+    /// Creates a PeerID from a 32-byte public key.
+    pub fn from_slice(key: &[u8]) -> Result<PeerID, Box<Error>> {
+        if key.len() != 32 {
+            return Err(From::from(format!(
+                "peer ID must be 32 bytes, got {}",
+                key.len()
+            )));
+        }
+        let mut id = [0u8; 32];
+        id.copy_from_slice(key);
+        Ok(PeerID(id))
+    }
+}
+
+impl fmt::Debug for PeerID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The first few bytes are enough to tell peers apart in the logs.
+        write!(f, "PeerID({}..)", hex::encode(&self.0[..6]))
+    }
+}
 
-use clock::VectorClock;
+impl serde::Serialize for PeerID {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&hex::encode(&self.0))
+    }
+}
 
-/// A PeerID is just an Endpoint.
-pub type PeerID = Endpoint;
+impl<'de> serde::Deserialize<'de> for PeerID {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let key = hex::decode(&s).map_err(serde::de::Error::custom)?;
+        PeerID::from_slice(&key).map_err(serde::de::Error::custom)
+    }
+}
 
-/// An Endpoint is a tuple of IPv4 address and TCP port.
-/// It serializes to a string representation so it can be used as a key for JSON maps.
+/// An Endpoint is a tuple of IP address (v4 or v6) and TCP port.
+/// It serializes to a string representation, e.g. `127.0.0.1:1234` or `[::1]:1234`.
 #[derive(Debug, Ord, PartialOrd, PartialEq, Eq, Hash, Copy, Clone)]
 pub struct Endpoint {
-    ip: net::Ipv4Addr,
+    ip: net::IpAddr,
     port: u16,
 }
 
@@ -31,7 +84,7 @@ impl serde::Serialize for Endpoint {
     where
         S: serde::Serializer,
     {
-        let s = format!("{}:{}", self.ip, self.port);
+        let s = SocketAddr::new(self.ip, self.port).to_string();
         serializer.serialize_str(&s)
     }
 }
@@ -43,20 +96,24 @@ impl<'de> serde::Deserialize<'de> for Endpoint {
     {
         let s = String::deserialize(deserializer)?;
 
-        // TODO maybe fix this
-        let addr = SocketAddr::from_str(&s).unwrap();
-        match addr {
-            SocketAddr::V6(_) => panic!("deserialized IPv6 address for endpoint?"),
-            SocketAddr::V4(v4) => Ok(Endpoint {
-                ip: v4.ip().clone(),
-                port: v4.port(),
-            }),
+        let addr = SocketAddr::from_str(&s).map_err(|e| {
+            serde::de::Error::custom(format!("invalid endpoint {:?}: {}", s, e))
+        })?;
+        Ok(Endpoint::from(addr))
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Endpoint {
+        Endpoint {
+            ip: addr.ip(),
+            port: addr.port(),
         }
     }
 }
 
 impl Endpoint {
-    pub fn new(ip: &net::Ipv4Addr, port: u16) -> Endpoint {
+    pub fn new(ip: &net::IpAddr, port: u16) -> Endpoint {
         Endpoint {
             ip: ip.clone(),
             port: port,
@@ -64,18 +121,87 @@ impl Endpoint {
     }
 }
 
-/// A CopyClock encapsulates a VectorClock and the PeerID of the peer who last pressed copy.
+/// A Node is the identity of a peer together with the endpoint it currently listens on.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Node {
+    pub id: PeerID,
+    pub endpoint: Endpoint,
+}
+
+/// A CopyClock encapsulates a VectorClock and the PeerID of the peer who last pressed copy, along
+/// with the endpoint that peer can be reached at.
+/// [time] is the timestamp of the copy, from the HybridClock of the peer who copied. It is zero
+/// for copies by peers that predate version 8.
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct CopyClock {
     pub clock: VectorClock<PeerID>,
     pub last_copy_src: PeerID,
+    pub last_copy_endpoint: Endpoint,
+    #[serde(default)]
+    pub time: HybridTimestamp,
 }
 
 impl CopyClock {
-    pub fn new(clock: &VectorClock<PeerID>, last_copy_src: &PeerID) -> CopyClock {
+    pub fn new(
+        clock: &VectorClock<PeerID>,
+        last_copy_src: &PeerID,
+        last_copy_endpoint: &Endpoint,
+    ) -> CopyClock {
         CopyClock {
             clock: clock.clone(),
             last_copy_src: last_copy_src.clone(),
+            last_copy_endpoint: last_copy_endpoint.clone(),
+            time: HybridTimestamp::default(),
+        }
+    }
+
+    /// Orders this state and [other] totally: by their clocks as `VectorClock::total_cmp` does,
+    /// and states with the same clock by the peer who copied and the endpoint it can be
+    /// reached at.
+    /// Only equal states compare Equal.
+    pub fn total_cmp(&self, other: &CopyClock) -> cmp::Ordering {
+        self.clock
+            .total_cmp(&other.clock)
+            .then_with(|| self.last_copy_src.cmp(&other.last_copy_src))
+            .then_with(|| self.last_copy_endpoint.cmp(&other.last_copy_endpoint))
+    }
+
+    /// Orders this state and [other] as [policy] says.
+    /// With `ConflictPolicy::Latest`, states are ordered by the time they were copied, falling
+    /// back to `total_cmp` for the same time. A HybridClock timestamps a copy after every copy
+    /// it knew of, so a state that happened after the other one by their clocks is greater.
+    /// Copies by older peers carry no timestamp, so they are older than timestamped ones and
+    /// ordered by their clocks among each other.
+    /// Comparing a single key like this keeps the order transitive for any mix of states.
+    pub fn resolve(&self, other: &CopyClock, policy: ConflictPolicy) -> cmp::Ordering {
+        match policy {
+            ConflictPolicy::Clock => self.total_cmp(other),
+            ConflictPolicy::Latest => self.time
+                .cmp(&other.time)
+                .then_with(|| self.total_cmp(other)),
+        }
+    }
+}
+
+/// A ConflictPolicy decides which of two concurrent copies wins.
+/// All nodes of an overlay must use the same policy to agree on the latest clipboard.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// The order of the vector clocks decides, see `VectorClock::total_cmp`.
+    Clock,
+    /// The copy that happened last by wall-clock time wins, as far as the clocks of the peers
+    /// agree.
+    Latest,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ConflictPolicy, String> {
+        match s {
+            "clock" => Ok(ConflictPolicy::Clock),
+            "latest" => Ok(ConflictPolicy::Latest),
+            _ => Err(format!("unknown conflict policy {}, expected clock or latest", s)),
         }
     }
 }
@@ -83,7 +209,8 @@ impl CopyClock {
 /// A MessageID is a 16-byte ID for a message, assumed to be unique.
 pub type MessageID = [u8; 16];
 
-fn generate_message_id() -> [u8; 16] {
+/// Generates a new, random MessageID.
+pub fn generate_message_id() -> [u8; 16] {
     let mut b = [0u8; 16];
 
     let mut i = 0;
@@ -97,12 +224,14 @@ fn generate_message_id() -> [u8; 16] {
 
 /// A Message is sent between two peers.
 /// Every message has at least an ID, a source, a TTL and a hop count.
+/// The source is given by its PeerID and the endpoint it currently listens on.
 /// Different message types have additional content.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     pub message_id: MessageID,
     pub message_type: MessageType,
     pub src_id: PeerID,
+    pub src_endpoint: Endpoint,
     pub ttl: u32,
     pub hop_count: u32,
 }
@@ -110,116 +239,724 @@ pub struct Message {
 /// A MessageType encodes the type of a message and all fields specific to that type.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum MessageType {
-    /// A JoinRequest is propagated through the network without changing its source ID.
-    /// That way, peers along the way know the ID (and by that the endpoint) of the new peer.
+    /// A JoinRequest is propagated through the network without changing its source.
+    /// That way, peers along the way know the ID and endpoint of the new peer.
     /// A JoinRequest is the first message sent on a `JoinConnection`.
     JoinRequest,
 
     /// A JoinResponse is reverse-path routed to the joining peer.
-    /// Its source ID is not modified during forwarding, so that the new peer knows the IDs (and by
-    /// that the endpoints) of existing peers.
-    JoinResponse { target: Endpoint },
+    /// Its source is not modified during forwarding, so that the new peer knows the IDs and
+    /// endpoints of existing peers.
+    JoinResponse { target: PeerID },
 
     /// A Ping is used for the soft-state protocol.
-    /// It contains the current state of the sending peer.
+    /// It contains the current state of the sending peer for CLIPBOARD and, if the peer supports
+    /// it, PRIMARY.
     /// Pings are not flooded through the network, but just ping-pong between two peers on a regular
     /// basis.
     /// A Ping is the first message sent on a `P2PConnection`.
-    Ping { state: CopyClock },
+    /// [departed] are the peers the sender saw leave or evicted, a clock entry is only folded once
+    /// all neighbours of a peer report it.
+    Ping {
+        state: CopyClock,
+        #[serde(default)]
+        primary: Option<CopyClock>,
+        #[serde(default)]
+        departed: Vec<PeerID>,
+    },
 
     /// A Pong is the reply to a Ping.
     /// The peer receiving the Ping updates its state if necessary and returns its own state with a
-    /// Pong.
-    Pong { state: CopyClock },
+    /// Pong, along with the peers it considers departed.
+    Pong {
+        state: CopyClock,
+        #[serde(default)]
+        primary: Option<CopyClock>,
+        #[serde(default)]
+        departed: Vec<PeerID>,
+    },
 
     /// A CopyNotification is flooded through the network from the peer who pressed copy.
-    CopyNotification { state: CopyClock },
+    /// Its state applies to [selection] only.
+    /// [digests] describe the content in every target, so that peers can check what they fetch
+    /// and don't need to fetch what they already have.
+    /// Small clipboards are pushed along as [content], so that peers don't need to fetch them at
+    /// all.
+    CopyNotification {
+        state: CopyClock,
+        #[serde(default)]
+        selection: Selection,
+        #[serde(default)]
+        digests: Vec<TargetDigest>,
+        #[serde(default)]
+        content: Option<ClipboardContent>,
+    },
 
     /// A CopyRequest is sent from a peer who pressed paste to the peer who last pressed copy.
     /// This is the first message sent on a `CopyConnection`, which is specifically opened between
     /// the two peers to exchange the clipboard.
-    CopyRequest { content_type: String },
+    /// [targets] lists the targets the requesting peer accepts, most preferred first.
+    /// It is empty for peers that only understand a TextResponse.
+    CopyRequest {
+        content_type: String,
+        #[serde(default)]
+        targets: Vec<String>,
+        #[serde(default)]
+        selection: Selection,
+    },
 
     /// A TextResponse is the response sent to a CopyRequest if the requested peer has the latest
-    /// clipboard with text content type.
+    /// clipboard with text content type and the requesting peer did not list any targets.
     TextResponse { text: String },
 
+    /// A BinaryResponse is the response sent to a CopyRequest that listed targets if the requested
+    /// peer has the latest clipboard.
+    /// It carries the data for the target negotiated from the requested targets.
+    BinaryResponse { target: String, data: Vec<u8> },
+
     /// An ErrorResponse is sent in response to a CopyRequest if the requested peer does not have
     /// the latest clipboard or not a textual clipboard.
     ErrorResponse { state: CopyClock, error: String },
+
+    /// A StreamRequest replaces the CopyRequest between peers that speak `STREAMING_VERSION`.
+    /// The clipboard is sent in chunks of at most [max_chunk] bytes, starting at [offset], which
+    /// is non-zero when resuming an interrupted transfer.
+    /// [compression] lists the compression methods the requesting peer accepts, most preferred
+    /// first.
+    StreamRequest {
+        targets: Vec<String>,
+        selection: Selection,
+        offset: u64,
+        max_chunk: u32,
+        compression: Vec<Compression>,
+    },
+
+    /// A StreamHeader is the response sent to a StreamRequest if the requested peer has the latest
+    /// clipboard.
+    /// It is followed by Chunks carrying the data for [target], compressed with [compression],
+    /// from [offset] up to [total_len]. [len] is the size of the data after decompression.
+    /// [state] identifies the content, a transfer can only be resumed if it did not change.
+    StreamHeader {
+        target: String,
+        state: CopyClock,
+        compression: Compression,
+        len: u64,
+        total_len: u64,
+        offset: u64,
+    },
+
+    /// A Chunk carries the next part of a streamed clipboard.
+    Chunk { data: Vec<u8> },
+
+    /// A Leave is sent on a `P2PConnection` by a peer that leaves the overlay, right before it
+    /// closes the connection.
+    /// [replacements] are other peers of the leaving peer the receiver may connect to instead.
+    /// [handover] holds the selections the leaving peer had the latest content of, it is only
+    /// sent to one peer, which fetches them with RelayRequests and takes them over. The leaving
+    /// peer keeps the connection open until the receiver closes it.
+    /// It is only sent to peers that speak `LEAVE_VERSION`, with a handover only to peers that
+    /// speak `HANDOVER_VERSION`.
+    Leave {
+        replacements: Vec<Node>,
+        handover: Vec<Handover>,
+    },
+
+    /// A ReplicaNotification is flooded through the network by a peer that fetched the latest
+    /// content of a selection, so that others can fetch it from there if the source is gone.
+    /// It is only sent to peers that speak `REPLICATION_VERSION`.
+    ReplicaNotification { replica: Replica },
+
+    /// A RelayRequest asks [source] for [selection] on behalf of a peer that can't connect to it.
+    /// It travels over P2PConnections like a CopyNotification, every peer on the way remembers
+    /// where it came from, so that the RelayResponses find their way back.
+    /// Like a StreamRequest, it lists the [targets] and [compression] methods the requesting peer
+    /// accepts, most preferred first, and the largest chunk it accepts.
+    /// It is only sent to peers that speak `RELAY_VERSION`.
+    RelayRequest {
+        source: PeerID,
+        selection: Selection,
+        targets: Vec<String>,
+        compression: Vec<Compression>,
+        max_chunk: u32,
+    },
+
+    /// A RelayResponse carries one part of the answer to the RelayRequest with the ID [request]
+    /// back to the requesting peer, hop by hop along the path the request took.
+    RelayResponse {
+        request: MessageID,
+        response: RelayAnswer,
+    },
+}
+
+/// A RelayAnswer is one part of the answer to a RelayRequest: a StreamHeader followed by Chunks,
+/// or an ErrorResponse, encoded like the MessageTypes of the same name.
+/// Unlike a MessageType it can't contain itself, so decoding it doesn't recurse.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RelayAnswer {
+    StreamHeader {
+        target: String,
+        state: CopyClock,
+        compression: Compression,
+        len: u64,
+        total_len: u64,
+        offset: u64,
+    },
+    Chunk { data: Vec<u8> },
+    ErrorResponse { state: CopyClock, error: String },
 }
 
-fn write_length_prefixed(conn: &mut net::TcpStream, msg: &Message) -> Result<(), Box<Error>> {
-    let serialized = serde_json::to_vec(&msg)?;
-    let len = serialized.len() as u32;
+/// A Handover passes the latest content of a selection to another peer when leaving the overlay.
+/// [state] is the state of the selection at the leaving peer, [digests] describe the content the
+/// other peer fetches.
+/// Peers before `HANDOVER_VERSION` send the [content] itself instead.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Handover {
+    pub selection: Selection,
+    pub state: CopyClock,
+    #[serde(default)]
+    pub digests: Vec<TargetDigest>,
+    #[serde(default)]
+    pub content: Option<ClipboardContent>,
+}
 
-    conn.write_u32::<BigEndian>(len)?;
-    let n = conn.write(&serialized.to_vec())?;
+/// A Replica announces that [holder] has the content of [selection] at [state] for [target],
+/// which has the digest [digest].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Replica {
+    pub selection: Selection,
+    pub state: CopyClock,
+    pub holder: Node,
+    pub target: String,
+    pub digest: ContentDigest,
+}
 
-    if n != serialized.len() {
-        // TODO we _could_ handle this nicely
+/// A ContentDigest is the BLAKE2s hash of the data of a clipboard target, along with the target.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Copy, Clone)]
+pub struct ContentDigest(pub [u8; 32]);
+
+impl ContentDigest {
+    /// Computes the digest of [data] for [target].
+    pub fn of(target: &str, data: &[u8]) -> ContentDigest {
+        let mut hasher = Blake2s256::new();
+        hasher.update(target.as_bytes());
+        // Separates the target from the data, targets never contain zero bytes.
+        hasher.update(&[0]);
+        hasher.update(data);
+        let mut digest = [0u8; 32];
+        digest.copy_from_slice(&hasher.finalize());
+        ContentDigest(digest)
+    }
+}
+
+impl fmt::Debug for ContentDigest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ContentDigest({}..)", hex::encode(&self.0[..6]))
+    }
+}
+
+/// A TargetDigest describes the data of one target of a clipboard by its size and digest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TargetDigest {
+    pub target: String,
+    pub len: u64,
+    pub digest: ContentDigest,
+}
+
+impl TargetDigest {
+    /// Computes the TargetDigests of all targets of [content].
+    pub fn of_content(content: &ClipboardContent) -> Vec<TargetDigest> {
+        content
+            .targets()
+            .into_iter()
+            .map(|target| {
+                let data = content.get(&target).unwrap();
+                TargetDigest {
+                    len: data.len() as u64,
+                    digest: ContentDigest::of(&target, data),
+                    target: target,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns whether [data] is the data described by this digest.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() as u64 == self.len && ContentDigest::of(&self.target, data) == self.digest
+    }
+}
+
+/// The version of the protocol spoken by this node.
+/// Nodes that predate version negotiation speak version 0, which always uses JSON.
+/// Version 4 added digests to CopyNotifications, version 5 pushes small clipboards with them.
+/// Version 6 relays clipboards over P2PConnections, version 7 folds the clock entries of
+/// departed peers into a base counter, version 8 adds timestamps to CopyClocks, version 9 hands
+/// over selections as digests that are fetched by relay, version 10 reports departed peers in
+/// Pings and Pongs and version 11 marks that Leaves are understood.
+pub const PROTOCOL_VERSION: u32 = 11;
+
+/// The first protocol version that streams clipboards in chunks.
+pub const STREAMING_VERSION: u32 = 2;
+
+/// The first protocol version that understands ReplicaNotifications and serves replicas.
+pub const REPLICATION_VERSION: u32 = 3;
+
+/// The first protocol version that relays clipboards over P2PConnections.
+pub const RELAY_VERSION: u32 = 6;
+
+/// The first protocol version that understands the base counter of VectorClocks, so that the
+/// entries of departed peers may be folded into it.
+pub const FOLDING_VERSION: u32 = 7;
+
+/// The first protocol version that takes over Handovers by fetching them.
+pub const HANDOVER_VERSION: u32 = 9;
+
+/// The first protocol version that surely understands Leaves.
+/// Leaves were added without a version of their own, so some nodes that speak an earlier version
+/// know them and some don't.
+pub const LEAVE_VERSION: u32 = 11;
+
+/// The maximum size of a single message we accept by default.
+/// Clipboards are streamed in chunks, so this only limits legacy responses.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// The size of the largest clipboard pushed along with a CopyNotification by default.
+pub const DEFAULT_PUSH_SIZE: usize = 4 * 1024;
+
+/// The size of the largest clipboard we accept from a stream, compressed or not.
+/// Streams are not limited by the maximum message size, this keeps a remote from making us
+/// allocate arbitrary amounts of memory.
+pub const MAX_CONTENT_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The size of the chunks a clipboard is streamed in, unless the requester asks for less.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How long an accepted peer may take to send its Hello and first message after the handshake.
+const ACCEPT_TIMEOUT_SECS: u64 = 5;
+
+/// A Codec determines how messages are serialized on the wire.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Codec {
+    /// JSON is verbose, but readable for debugging and understood by every node.
+    Json,
+
+    /// bincode is compact, byte arrays are sent as-is.
+    Bincode,
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Codec, String> {
+        match s {
+            "json" => Ok(Codec::Json),
+            "bincode" => Ok(Codec::Bincode),
+            _ => Err(format!("unknown codec {}, expected json or bincode", s)),
+        }
+    }
+}
+
+/// A Compression is a method to compress clipboard content with on the wire.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Deflate,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Compression, String> {
+        match s {
+            "none" => Ok(Compression::None),
+            "deflate" => Ok(Compression::Deflate),
+            _ => Err(format!("unknown compression {}, expected none or deflate", s)),
+        }
+    }
+}
+
+/// Content smaller than this is not worth compressing.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Compresses [data] with [compression].
+fn compress(compression: Compression, data: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Deflate => {
+            let mut e = flate2::write::DeflateEncoder::new(
+                Vec::with_capacity(data.len() / 2),
+                flate2::Compression::default(),
+            );
+            e.write_all(data)?;
+            Ok(e.finish()?)
+        }
+    }
+}
+
+/// Decompresses [data] with [compression], expecting exactly [len] bytes of output.
+fn decompress(compression: Compression, data: Vec<u8>, len: u64) -> Result<Vec<u8>, Box<Error>> {
+    // The announced length is up to the remote, don't inflate a few bytes into gigabytes.
+    if len > MAX_CONTENT_SIZE {
         return Err(From::from(format!(
-            "Unable to send, wrote {} bytes, expected {}",
-            n,
-            serialized.len()
+            "content of {} bytes exceeds maximum of {} bytes",
+            len, MAX_CONTENT_SIZE
         )));
     }
+    let out = match compression {
+        Compression::None => data,
+        Compression::Deflate => {
+            // Never inflate beyond what was announced.
+            let mut out = Vec::new();
+            flate2::read::DeflateDecoder::new(&data[..])
+                .take(len + 1)
+                .read_to_end(&mut out)?;
+            out
+        }
+    };
+    if out.len() as u64 != len {
+        return Err(From::from(format!(
+            "expected {} bytes of content, got {}",
+            len,
+            out.len()
+        )));
+    }
+    Ok(out)
+}
+
+/// A Hello is exchanged right after the handshake to agree on a protocol version and codec.
+/// The initiator lists the codecs it supports, most preferred first; the responder answers with
+/// its own version and the chosen codec.
+/// Hellos are always JSON-encoded.
+#[derive(Serialize, Deserialize, Debug)]
+struct Hello {
+    version: u32,
+    codecs: Vec<Codec>,
+}
+
+/// A Protocol is what two peers agreed on for a connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Protocol {
+    pub version: u32,
+    pub codec: Codec,
+}
+
+impl Protocol {
+    /// The protocol of peers that predate negotiation.
+    fn legacy() -> Protocol {
+        Protocol {
+            version: 0,
+            codec: Codec::Json,
+        }
+    }
+}
+
+/// A ConnectionConfig holds what is needed to establish connections: the keys to authenticate
+/// with, the codecs and compression methods to offer, most preferred first, the size of the
+/// largest message we read, and the size of the largest clipboard we push to peers.
+pub struct ConnectionConfig {
+    pub keys: Keys,
+    pub codecs: Vec<Codec>,
+    pub compression: Vec<Compression>,
+    pub max_message_size: usize,
+    pub push_size: usize,
+    pub conflict_policy: ConflictPolicy,
+}
+
+impl ConnectionConfig {
+    /// Creates a ConnectionConfig preferring bincode and falling back to JSON.
+    pub fn new(keys: Keys) -> ConnectionConfig {
+        ConnectionConfig {
+            keys: keys,
+            codecs: vec![Codec::Bincode, Codec::Json],
+            compression: vec![Compression::Deflate],
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            push_size: DEFAULT_PUSH_SIZE,
+            conflict_policy: ConflictPolicy::Clock,
+        }
+    }
+
+    /// Returns the chunk size to request so that every Chunk fits into a message.
+    /// JSON spends up to four bytes on every byte of data, so this leaves plenty of room.
+    pub fn max_chunk(&self) -> u32 {
+        cmp::max(cmp::min(CHUNK_SIZE, self.max_message_size / 8), 1) as u32
+    }
+
+    /// Returns the size of the largest clipboard to push, so that every CopyNotification fits
+    /// into a message. As for Chunks, the content takes at most half of the message in JSON,
+    /// which leaves room for the state and the digests.
+    pub fn max_push_size(&self) -> usize {
+        cmp::min(self.push_size, self.max_message_size / 8)
+    }
+}
+
+fn encode(codec: Codec, msg: &Message) -> Result<Vec<u8>, Box<Error>> {
+    match codec {
+        Codec::Json => Ok(serde_json::to_vec(msg)?),
+        Codec::Bincode => Ok(bincode::serialize(msg)?),
+    }
+}
+
+fn decode(codec: Codec, buf: &[u8]) -> Result<Message, Box<Error>> {
+    match codec {
+        Codec::Json => Ok(serde_json::from_slice(buf)?),
+        Codec::Bincode => Ok(bincode::deserialize(buf)?),
+    }
+}
+
+fn write_frame<W: Write>(conn: &mut W, data: &[u8]) -> Result<(), Box<Error>> {
+    // Write length and data in one go, a SecureStream sends every write as a whole, so the
+    // writes of other threads don't end up in the middle of the message.
+    let mut buf = Vec::with_capacity(data.len() + 4);
+    buf.write_u32::<BigEndian>(data.len() as u32)?;
+    buf.extend_from_slice(data);
+    conn.write_all(&buf)?;
 
     Ok(())
 }
 
-fn read_length_prefixed(r: &mut TcpStream) -> Result<Message, Box<Error>> {
+fn read_frame<R: Read>(r: &mut R, max_len: usize) -> Result<Vec<u8>, Box<Error>> {
     let len = r.read_u32::<BigEndian>()?;
-
-    // we just need a slice of length len, how hard can it be...
-    let mut buf = Vec::with_capacity(len as usize);
-    for i in 0..len {
-        buf.push(0);
+    // Don't let the remote make us allocate arbitrary amounts of memory.
+    if len as usize > max_len {
+        return Err(From::from(format!(
+            "message of {} bytes exceeds maximum of {} bytes",
+            len, max_len
+        )));
     }
 
+    let mut buf = vec![0u8; len as usize];
     r.read_exact(buf.as_mut_slice())?;
 
-    let deserialized: Message = serde_json::from_slice(&buf)?;
+    Ok(buf)
+}
+
+fn write_length_prefixed<W: Write>(
+    conn: &mut W,
+    proto: Protocol,
+    msg: &Message,
+) -> Result<(), Box<Error>> {
+    write_frame(conn, &encode(proto.codec, msg)?)
+}
+
+fn read_length_prefixed<R: Read>(
+    r: &mut R,
+    proto: Protocol,
+    max_len: usize,
+) -> Result<Message, Box<Error>> {
+    decode(proto.codec, &read_frame(r, max_len)?)
+}
+
+/// Returns whether [codec] can be used with a peer speaking [version].
+/// bincode has no room for fields added to messages in later versions, so it is only used between
+/// peers of the same version. JSON ignores unknown fields and fills in missing ones.
+fn codec_supports(codec: Codec, version: u32) -> bool {
+    codec != Codec::Bincode || version == PROTOCOL_VERSION
+}
+
+/// Connects to [remote] and agrees on a protocol.
+/// Peers that predate negotiation drop the connection when they receive a Hello, in that case we
+/// reconnect and speak the legacy protocol.
+/// Peers of other versions may choose bincode, in that case we reconnect and only offer JSON.
+fn open_stream(
+    remote: &Endpoint,
+    config: &ConnectionConfig,
+) -> Result<(SecureStream, Protocol), Box<Error>> {
+    let (stream, proto) = open_stream_with(remote, config, &config.codecs)?;
+    if codec_supports(proto.codec, proto.version) {
+        return Ok((stream, proto));
+    }
+    let codecs: Vec<Codec> = config
+        .codecs
+        .iter()
+        .cloned()
+        .filter(|c| codec_supports(*c, proto.version))
+        .collect();
+    if codecs.is_empty() {
+        return Err(From::from(format!(
+            "{:?} speaks version {}, none of our codecs {:?} work with it",
+            remote, proto.version, config.codecs
+        )));
+    }
+    println!(
+        "network: {:?} speaks version {}, reconnecting with {:?}",
+        remote, proto.version, codecs
+    );
+    open_stream_with(remote, config, &codecs)
+}
+
+/// Connects to [remote] and agrees on a protocol, offering [codecs].
+fn open_stream_with(
+    remote: &Endpoint,
+    config: &ConnectionConfig,
+    codecs: &[Codec],
+) -> Result<(SecureStream, Protocol), Box<Error>> {
+    let addr = SocketAddr::from((remote.ip, remote.port));
+    let mut stream = SecureStream::connect(addr, &config.keys)?;
+
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+        codecs: codecs.to_vec(),
+    };
+    write_frame(&mut stream, &serde_json::to_vec(&hello)?)?;
+    let reply = read_frame(&mut stream, config.max_message_size)
+        .and_then(|buf| serde_json::from_slice::<Hello>(&buf).map_err(From::from));
+
+    match reply {
+        Ok(reply) => {
+            let codec = match reply.codecs.first() {
+                Some(codec) if codecs.contains(codec) => *codec,
+                _ => {
+                    return Err(From::from(format!(
+                        "{:?} chose none of our codecs: {:?}",
+                        remote, reply.codecs
+                    )))
+                }
+            };
+            let proto = Protocol {
+                version: cmp::min(reply.version, PROTOCOL_VERSION),
+                codec: codec,
+            };
+            Ok((stream, proto))
+        }
+        Err(ref e) if closed_by_peer(e) && codecs.contains(&Codec::Json) => {
+            println!("network: no hello from {:?} ({}), assuming legacy peer", remote, e);
+            let stream = SecureStream::connect(addr, &config.keys)?;
+            Ok((stream, Protocol::legacy()))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns whether [e] means the remote closed the connection, which is how legacy peers react
+/// to a Hello.
+fn closed_by_peer(e: &Box<Error>) -> bool {
+    match e.downcast_ref::<io::Error>() {
+        Some(e) => match e.kind() {
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset => true,
+            _ => false,
+        },
+        None => false,
+    }
+}
 
-    Ok(deserialized)
+/// Authenticates an incoming [stream], agrees on a protocol and reads the first message.
+/// Peers that predate negotiation send their first message right away, in JSON.
+fn accept_stream(
+    stream: net::TcpStream,
+    config: &ConnectionConfig,
+) -> Result<(SecureStream, Protocol, Message), Box<Error>> {
+    let mut stream = SecureStream::accept(stream, &config.keys)?;
+    // Connections are accepted one after the other, don't let a silent peer block the others.
+    stream.set_timeouts(Some(time::Duration::from_secs(ACCEPT_TIMEOUT_SECS)), None)?;
+
+    let first = read_frame(&mut stream, config.max_message_size)?;
+    let hello = match serde_json::from_slice::<Hello>(&first) {
+        Ok(hello) => hello,
+        Err(_) => {
+            let msg = decode(Codec::Json, &first)?;
+            stream.set_timeouts(None, None)?;
+            return Ok((stream, Protocol::legacy(), msg));
+        }
+    };
+
+    let codec = *hello
+        .codecs
+        .iter()
+        .find(|c| config.codecs.contains(c) && codec_supports(**c, hello.version))
+        .ok_or(format!(
+            "no common codec for version {}, remote offered {:?}",
+            hello.version, hello.codecs
+        ))?;
+    let reply = Hello {
+        version: PROTOCOL_VERSION,
+        codecs: vec![codec],
+    };
+    write_frame(&mut stream, &serde_json::to_vec(&reply)?)?;
+
+    let proto = Protocol {
+        version: cmp::min(hello.version, PROTOCOL_VERSION),
+        codec: codec,
+    };
+    let msg = read_length_prefixed(&mut stream, proto, config.max_message_size)?;
+    stream.set_timeouts(None, None)?;
+    Ok((stream, proto, msg))
 }
 
 /// Starts accepting incoming connections on the given socket, returning an IncomingConnection
 /// on success.
+/// Every connection starts with a handshake using the keys in [config], connections from peers
+/// that fail to authenticate are dropped.
 /// This function determines the type of the incoming connection by its first message, which is
 /// returned as part of the IncomingConnection.
-pub fn accept(socket: &mut net::TcpListener) -> Result<IncomingConnection, Box<Error>> {
+pub fn accept(
+    socket: &mut net::TcpListener,
+    config: &ConnectionConfig,
+) -> Result<IncomingConnection, Box<Error>> {
     for conn in socket.incoming() {
         if let Err(err) = conn {
             return Err(From::from(err));
         }
-        let mut stream = conn?;
+        let stream = conn?;
+        let addr = stream.peer_addr();
+        // Streams may inherit non-blocking mode from a polled listener on some platforms.
+        if let Err(e) = stream.set_nonblocking(false) {
+            println!("accept: rejecting {:?}: {}", addr, e);
+            continue;
+        }
+
+        let (stream, proto, deserialized) = match accept_stream(stream, config) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("accept: rejecting {:?}: {}", addr, e);
+                continue;
+            }
+        };
 
-        let deserialized = read_length_prefixed(&mut stream)?;
+        // Pings and CopyRequests come straight from their source, which must be the peer that
+        // authenticated. JoinRequests may have been forwarded.
+        match deserialized.message_type {
+            MessageType::Ping { .. }
+            | MessageType::CopyRequest { .. }
+            | MessageType::StreamRequest { .. } => {
+                if stream.remote_static() != &deserialized.src_id.0[..] {
+                    println!(
+                        "accept: rejecting {:?}: claims to be {:?}, but authenticated differently",
+                        addr, deserialized.src_id
+                    );
+                    continue;
+                }
+            }
+            _ => (),
+        }
 
         match deserialized.message_type {
             MessageType::JoinRequest => {
                 return Ok(IncomingConnection {
                     conn: Connection::Join(JoinConnection {
                         conn: stream,
+                        proto: proto,
+                        max_message_size: config.max_message_size,
                         dir: Direction::Incoming,
                     }),
                     first_msg: deserialized,
                 });
             }
-            MessageType::Ping { state: _ } => {
+            MessageType::Ping { .. } => {
                 return Ok(IncomingConnection {
                     conn: Connection::P2P(P2PConnection {
                         conn: stream,
+                        proto: proto,
+                        max_message_size: config.max_message_size,
                         dir: Direction::Incoming,
                     }),
                     first_msg: deserialized,
                 });
             }
-            MessageType::CopyRequest { content_type: _ } => {
+            MessageType::CopyRequest { .. } | MessageType::StreamRequest { .. } => {
                 return Ok(IncomingConnection {
                     conn: Connection::Copy(CopyConnection {
                         conn: stream,
+                        proto: proto,
+                        max_message_size: config.max_message_size,
                         dir: Direction::Incoming,
                     }),
                     first_msg: deserialized,
@@ -264,51 +1001,70 @@ pub enum Direction {
 /// for more peers.
 #[derive(Debug)]
 pub struct JoinConnection {
-    conn: net::TcpStream,
+    conn: SecureStream,
+    proto: Protocol,
+    max_message_size: usize,
     dir: Direction,
 }
 
 impl JoinConnection {
-    fn connect(remote: &PeerID, msg: Message) -> Result<JoinConnection, Box<Error>> {
-        let mut stream = TcpStream::connect(SocketAddr::from((remote.ip, remote.port)))?;
+    fn connect(
+        remote: &Endpoint,
+        msg: Message,
+        config: &ConnectionConfig,
+    ) -> Result<JoinConnection, Box<Error>> {
+        let (mut stream, proto) = open_stream(remote, config)?;
 
-        write_length_prefixed(&mut stream, &msg)?;
+        write_length_prefixed(&mut stream, proto, &msg)?;
 
         Ok(JoinConnection {
             conn: stream,
+            proto: proto,
+            max_message_size: config.max_message_size,
             dir: Direction::Outgoing,
         })
     }
 
     /// Opens a new connection to [remote], presenting [local] as the joining peer.
-    pub fn open(local: &PeerID, remote: &PeerID, ttl: u32) -> Result<JoinConnection, Box<Error>> {
+    pub fn open(
+        local: &Node,
+        remote: &Endpoint,
+        ttl: u32,
+        config: &ConnectionConfig,
+    ) -> Result<JoinConnection, Box<Error>> {
         let msg = Message {
             message_id: generate_message_id(),
             message_type: MessageType::JoinRequest,
-            src_id: local.clone(),
+            src_id: local.id,
+            src_endpoint: local.endpoint,
             ttl: ttl,
             hop_count: 0,
         };
 
-        JoinConnection::connect(remote, msg)
+        JoinConnection::connect(remote, msg, config)
     }
 
     /// Opens a new connection to [remote], forwarding [incoming] as part of the flooding procedure.
-    pub fn forward(remote: &PeerID, incoming: &Message) -> Result<JoinConnection, Box<Error>> {
+    pub fn forward(
+        remote: &Endpoint,
+        incoming: &Message,
+        config: &ConnectionConfig,
+    ) -> Result<JoinConnection, Box<Error>> {
         let msg = Message {
             message_id: incoming.message_id,
             message_type: MessageType::JoinRequest,
             src_id: incoming.src_id.clone(),
+            src_endpoint: incoming.src_endpoint.clone(),
             ttl: incoming.ttl - 1,
             hop_count: incoming.hop_count + 1,
         };
 
-        JoinConnection::connect(remote, msg)
+        JoinConnection::connect(remote, msg, config)
     }
 
-    /// Responds to [incoming] with [own_id] as the ID.
+    /// Responds to [incoming] with [local] as the source.
     /// The response is then reverse-path routed to the original sender.
-    pub fn respond(&mut self, own_id: &PeerID, incoming: &Message) -> Result<(), Box<Error>> {
+    pub fn respond(&mut self, local: &Node, incoming: &Message) -> Result<(), Box<Error>> {
         if self.dir != Direction::Incoming {
             return Err(From::from("can only respond on incoming connections"));
         }
@@ -318,19 +1074,20 @@ impl JoinConnection {
             message_type: MessageType::JoinResponse {
                 target: incoming.src_id.clone(),
             },
-            src_id: own_id.clone(),
+            src_id: local.id,
+            src_endpoint: local.endpoint,
             ttl: incoming.ttl,
             hop_count: incoming.hop_count,
         };
 
-        write_length_prefixed(&mut self.conn, &msg)
+        write_length_prefixed(&mut self.conn, self.proto, &msg)
     }
 
     /// Forwards a response via reverse-path routing to [target].
     pub fn forward_response(
         &mut self,
         incoming: &Message,
-        target: &Endpoint,
+        target: &PeerID,
     ) -> Result<(), Box<Error>> {
         if self.dir != Direction::Incoming {
             return Err(From::from("can only respond on incoming connections"));
@@ -342,11 +1099,12 @@ impl JoinConnection {
                 target: target.clone(),
             },
             src_id: incoming.src_id.clone(),
+            src_endpoint: incoming.src_endpoint.clone(),
             ttl: incoming.ttl,
             hop_count: incoming.hop_count,
         };
 
-        write_length_prefixed(&mut self.conn, &msg)
+        write_length_prefixed(&mut self.conn, self.proto, &msg)
     }
 
     /// Reads a message off the underlying socket, iff this is an outgoing connection.
@@ -355,7 +1113,7 @@ impl JoinConnection {
             return Err(From::from("can only read on outgoing JoinConnection"));
         }
 
-        read_length_prefixed(&mut self.conn)
+        read_length_prefixed(&mut self.conn, self.proto, self.max_message_size)
     }
 
     /// Flushes and closes the connection.
@@ -370,56 +1128,171 @@ impl JoinConnection {
 /// and a peer who is believed to have the clipboard.
 #[derive(Debug)]
 pub struct CopyConnection {
-    conn: net::TcpStream,
+    conn: SecureStream,
+    proto: Protocol,
+    max_message_size: usize,
     dir: Direction,
 }
 
 impl CopyConnection {
-    fn connect(remote: &PeerID, msg: Message) -> Result<CopyConnection, Box<Error>> {
-        let mut stream = TcpStream::connect(SocketAddr::from((remote.ip, remote.port)))?;
-
-        write_length_prefixed(&mut stream, &msg)?;
+    /// Opens a new CopyConnection to [remote], requesting [selection] in one of [targets].
+    /// Peers that support streaming are asked to start at [offset], the data of older peers
+    /// always arrives in one piece, so they can not resume a transfer.
+    pub fn open(
+        local: &Node,
+        remote: &Endpoint,
+        selection: Selection,
+        targets: &[String],
+        offset: u64,
+        config: &ConnectionConfig,
+    ) -> Result<CopyConnection, Box<Error>> {
+        let (mut stream, proto) = open_stream(remote, config)?;
+
+        let message_type = if proto.version >= STREAMING_VERSION {
+            MessageType::StreamRequest {
+                targets: targets.to_vec(),
+                selection: selection,
+                offset: offset,
+                max_chunk: config.max_chunk(),
+                compression: config.compression.clone(),
+            }
+        } else if offset == 0 {
+            MessageType::CopyRequest {
+                content_type: "text".to_string(),
+                targets: targets.to_vec(),
+                selection: selection,
+            }
+        } else {
+            return Err(From::from(format!(
+                "{:?} speaks protocol version {}, unable to resume",
+                remote, proto.version
+            )));
+        };
+        let msg = Message {
+            message_id: generate_message_id(),
+            message_type: message_type,
+            src_id: local.id,
+            src_endpoint: local.endpoint,
+            ttl: 1,
+            hop_count: 0,
+        };
+        write_length_prefixed(&mut stream, proto, &msg)?;
 
         Ok(CopyConnection {
             conn: stream,
+            proto: proto,
+            max_message_size: config.max_message_size,
             dir: Direction::Outgoing,
         })
     }
 
-    /// Opens a new CopyConnection to [remote].
-    pub fn open(
-        local: &PeerID,
-        remote: &PeerID,
-        content_type: &String,
-    ) -> Result<CopyConnection, Box<Error>> {
+    /// Responds to the request with the textual contents of the clipboard.
+    pub fn respond(&mut self, text: &String, local: &Node) -> Result<(), Box<Error>> {
+        if self.dir != Direction::Incoming {
+            return Err(From::from("can only respond on incoming connection"));
+        }
+
         let msg = Message {
             message_id: generate_message_id(),
-            message_type: MessageType::CopyRequest {
-                content_type: content_type.clone(),
+            message_type: MessageType::TextResponse { text: text.clone() },
+            src_id: local.id,
+            src_endpoint: local.endpoint,
+            ttl: 1,
+            hop_count: 0,
+        };
+
+        write_length_prefixed(&mut self.conn, self.proto, &msg)
+    }
+
+    /// Responds to the request with the contents of the clipboard for [target].
+    pub fn respond_binary(
+        &mut self,
+        target: &String,
+        data: &[u8],
+        local: &Node,
+    ) -> Result<(), Box<Error>> {
+        if self.dir != Direction::Incoming {
+            return Err(From::from("can only respond on incoming connection"));
+        }
+
+        let msg = Message {
+            message_id: generate_message_id(),
+            message_type: MessageType::BinaryResponse {
+                target: target.clone(),
+                data: data.to_vec(),
             },
-            src_id: local.clone(),
+            src_id: local.id,
+            src_endpoint: local.endpoint,
             ttl: 1,
             hop_count: 0,
         };
 
-        CopyConnection::connect(remote, msg)
+        write_length_prefixed(&mut self.conn, self.proto, &msg)
     }
 
-    /// Responds to the request with the contents of the clipboard.
-    pub fn respond(&mut self, text: &String, local: &PeerID) -> Result<(), Box<Error>> {
+    /// Responds to a StreamRequest with the contents of the clipboard for [target], starting at
+    /// [offset] and split into chunks of at most [max_chunk] bytes.
+    /// Content of at least `COMPRESSION_THRESHOLD` bytes is compressed with the first of
+    /// [compression] we support, if that makes it smaller.
+    /// Compression is deterministic, so the offset of a resumed transfer refers to the same data.
+    /// [state] is the state of the selection the content belongs to.
+    /// Returns the size of the content and the number of bytes it took on the wire.
+    pub fn respond_stream(
+        &mut self,
+        target: &String,
+        data: &[u8],
+        offset: u64,
+        max_chunk: u32,
+        compression: &[Compression],
+        state: &CopyClock,
+        local: &Node,
+    ) -> Result<(u64, u64), Box<Error>> {
         if self.dir != Direction::Incoming {
             return Err(From::from("can only respond on incoming connection"));
         }
 
+        let (method, wire) = prepare_stream(data, compression)?;
+        if offset > wire.len() as u64 {
+            return Err(From::from(format!(
+                "offset {} is beyond the end of {} bytes",
+                offset,
+                wire.len()
+            )));
+        }
+
         let msg = Message {
             message_id: generate_message_id(),
-            message_type: MessageType::TextResponse { text: text.clone() },
-            src_id: local.clone(),
+            message_type: MessageType::StreamHeader {
+                target: target.clone(),
+                state: state.clone(),
+                compression: method,
+                len: data.len() as u64,
+                total_len: wire.len() as u64,
+                offset: offset,
+            },
+            src_id: local.id,
+            src_endpoint: local.endpoint,
             ttl: 1,
             hop_count: 0,
         };
+        write_length_prefixed(&mut self.conn, self.proto, &msg)?;
+
+        let chunk_size = cmp::max(cmp::min(max_chunk as usize, CHUNK_SIZE), 1);
+        for chunk in wire[offset as usize..].chunks(chunk_size) {
+            let msg = Message {
+                message_id: generate_message_id(),
+                message_type: MessageType::Chunk {
+                    data: chunk.to_vec(),
+                },
+                src_id: local.id,
+                src_endpoint: local.endpoint,
+                ttl: 1,
+                hop_count: 0,
+            };
+            write_length_prefixed(&mut self.conn, self.proto, &msg)?;
+        }
 
-        write_length_prefixed(&mut self.conn, &msg)
+        Ok((data.len() as u64, wire.len() as u64 - offset))
     }
 
     /// Responds to the request with an error and the local state.
@@ -427,7 +1300,7 @@ impl CopyConnection {
         &mut self,
         error: &String,
         state: &CopyClock,
-        local: &Endpoint,
+        local: &Node,
     ) -> Result<(), Box<Error>> {
         if self.dir != Direction::Incoming {
             return Err(From::from("can only respond on incoming connection"));
@@ -439,12 +1312,18 @@ impl CopyConnection {
                 error: error.clone(),
                 state: state.clone(),
             },
-            src_id: local.clone(),
+            src_id: local.id,
+            src_endpoint: local.endpoint,
             ttl: 1,
             hop_count: 0,
         };
 
-        write_length_prefixed(&mut self.conn, &msg)
+        write_length_prefixed(&mut self.conn, self.proto, &msg)
+    }
+
+    /// Returns the ID the remote peer authenticated with.
+    pub fn remote_id(&self) -> Result<PeerID, Box<Error>> {
+        PeerID::from_slice(self.conn.remote_static())
     }
 
     /// Reads a message (the response) off the underlying socket, iff this is an outgoing
@@ -454,7 +1333,80 @@ impl CopyConnection {
             return Err(From::from("can only read on outgoing CopyConnection"));
         }
 
-        read_length_prefixed(&mut self.conn)
+        read_length_prefixed(&mut self.conn, self.proto, self.max_message_size)
+    }
+
+    /// Receives the Chunks following [header], a StreamHeader, into [transfer].
+    /// [transfer] holds what was received before the previous connection broke, if anything.
+    /// It is replaced if the transfer starts from scratch and keeps the data received so far if
+    /// this connection breaks, so that the transfer can be resumed.
+    /// [progress] is called with the number of bytes received so far and the total after every
+    /// chunk.
+    pub fn read_stream(
+        &mut self,
+        header: Message,
+        transfer: &mut Option<Transfer>,
+        progress: &mut FnMut(u64, u64),
+    ) -> Result<(), Box<Error>> {
+        let (target, state, compression, len, total_len, offset) = match header.message_type {
+            MessageType::StreamHeader {
+                target,
+                state,
+                compression,
+                len,
+                total_len,
+                offset,
+            } => (target, state, compression, len, total_len, offset),
+            t => return Err(From::from(format!("expected a StreamHeader, got {:?}", t))),
+        };
+
+        if offset == 0 {
+            *transfer = Some(Transfer::new(target, state, compression, len, total_len)?);
+        } else {
+            let resumable = match *transfer {
+                Some(ref t) => {
+                    t.target == target
+                        && t.state == state
+                        && t.compression == compression
+                        && t.len == len
+                        && t.total_len == total_len
+                        && t.received() == offset
+                }
+                None => false,
+            };
+            if !resumable {
+                *transfer = None;
+                return Err(From::from(format!(
+                    "remote resumed a different transfer of {} at offset {}",
+                    target, offset
+                )));
+            }
+        }
+
+        let transfer = transfer.as_mut().unwrap();
+        progress(transfer.received(), transfer.total_len);
+        while !transfer.is_complete() {
+            let msg = self.read_message()?;
+            let data = match msg.message_type {
+                MessageType::Chunk { data } => data,
+                t => return Err(From::from(format!("expected a Chunk, got {:?}", t))),
+            };
+            transfer.push(&data)?;
+            progress(transfer.received(), transfer.total_len);
+        }
+
+        Ok(())
+    }
+
+    /// Sets the read and write timeouts of the connection.
+    /// Reads and writes that time out fail and leave the connection unusable.
+    pub fn set_timeouts(
+        &self,
+        read: time::Duration,
+        write: time::Duration,
+    ) -> Result<(), Box<Error>> {
+        self.conn.set_timeouts(Some(read), Some(write))?;
+        Ok(())
     }
 
     /// Flushes and closes the connection.
@@ -465,43 +1417,203 @@ impl CopyConnection {
     }
 }
 
+/// Compresses [data] for streaming if it has at least `COMPRESSION_THRESHOLD` bytes, with the
+/// first of [compression] we support, if that makes it smaller.
+/// Returns the method used and the data to send.
+fn prepare_stream(
+    data: &[u8],
+    compression: &[Compression],
+) -> Result<(Compression, Vec<u8>), Box<Error>> {
+    let method = if data.len() < COMPRESSION_THRESHOLD {
+        Compression::None
+    } else {
+        compression.first().cloned().unwrap_or(Compression::None)
+    };
+    let compressed = compress(method, data)?;
+    if compressed.len() < data.len() {
+        Ok((method, compressed))
+    } else {
+        Ok((Compression::None, data.to_vec()))
+    }
+}
+
+/// Returns the StreamHeader and Chunks that stream [data] for [target] from the beginning, as
+/// `CopyConnection::respond_stream` does, for relaying them over P2PConnections.
+/// The data is compressed and chunked like there, [state] is the state of the selection it
+/// belongs to.
+pub fn stream_messages(
+    target: &String,
+    data: &[u8],
+    max_chunk: u32,
+    compression: &[Compression],
+    state: &CopyClock,
+) -> Result<Vec<RelayAnswer>, Box<Error>> {
+    let (method, wire) = prepare_stream(data, compression)?;
+    let mut messages = vec![RelayAnswer::StreamHeader {
+        target: target.clone(),
+        state: state.clone(),
+        compression: method,
+        len: data.len() as u64,
+        total_len: wire.len() as u64,
+        offset: 0,
+    }];
+    let chunk_size = cmp::max(cmp::min(max_chunk as usize, CHUNK_SIZE), 1);
+    for chunk in wire.chunks(chunk_size) {
+        messages.push(RelayAnswer::Chunk {
+            data: chunk.to_vec(),
+        });
+    }
+    Ok(messages)
+}
+
+/// A Transfer is the part of a streamed clipboard received so far.
+/// An interrupted transfer is resumed by requesting the clipboard again starting at `received()`.
+/// [data] is still compressed with [compression], [len] is its size after decompression.
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub target: String,
+    pub state: CopyClock,
+    pub compression: Compression,
+    pub len: u64,
+    pub total_len: u64,
+    pub data: Vec<u8>,
+}
+
+impl Transfer {
+    /// Starts a transfer announced by a StreamHeader, failing if it exceeds MAX_CONTENT_SIZE.
+    pub fn new(
+        target: String,
+        state: CopyClock,
+        compression: Compression,
+        len: u64,
+        total_len: u64,
+    ) -> Result<Transfer, Box<Error>> {
+        if len > MAX_CONTENT_SIZE || total_len > MAX_CONTENT_SIZE {
+            return Err(From::from(format!(
+                "{} of {} bytes exceeds maximum of {} bytes",
+                target,
+                cmp::max(len, total_len),
+                MAX_CONTENT_SIZE
+            )));
+        }
+        Ok(Transfer {
+            target: target,
+            state: state,
+            compression: compression,
+            len: len,
+            total_len: total_len,
+            data: Vec::new(),
+        })
+    }
+
+    /// Decompresses the data of a complete transfer.
+    pub fn content(self) -> Result<Vec<u8>, Box<Error>> {
+        if !self.is_complete() {
+            return Err(From::from("transfer is not complete"));
+        }
+        decompress(self.compression, self.data, self.len)
+    }
+
+    /// Returns the number of bytes received so far.
+    pub fn received(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// Returns true if all data has been received.
+    pub fn is_complete(&self) -> bool {
+        self.received() == self.total_len
+    }
+
+    /// Appends the data of a Chunk.
+    pub fn push(&mut self, data: &[u8]) -> Result<(), Box<Error>> {
+        let received = self.received() + data.len() as u64;
+        if received > self.total_len || received > MAX_CONTENT_SIZE {
+            return Err(From::from(format!(
+                "remote sent more than the announced {} bytes",
+                self.total_len
+            )));
+        }
+        self.data.extend_from_slice(data);
+        Ok(())
+    }
+}
+
 /// A P2PConnection is the type of connection upheld between peers to exchange copy notifications
 /// and soft state updates.
 #[derive(Debug)]
 pub struct P2PConnection {
-    conn: net::TcpStream,
+    conn: SecureStream,
+    proto: Protocol,
+    max_message_size: usize,
     dir: Direction,
 }
 
 impl P2PConnection {
-    fn connect(remote: &PeerID, msg: Message) -> Result<P2PConnection, Box<Error>> {
-        let mut stream = TcpStream::connect(SocketAddr::from((remote.ip, remote.port)))?;
+    fn connect(
+        remote: &Endpoint,
+        msg: Message,
+        config: &ConnectionConfig,
+    ) -> Result<P2PConnection, Box<Error>> {
+        let (mut stream, proto) = open_stream(remote, config)?;
 
-        write_length_prefixed(&mut stream, &msg)?;
+        write_length_prefixed(&mut stream, proto, &msg)?;
 
         Ok(P2PConnection {
             conn: stream,
+            proto: proto,
+            max_message_size: config.max_message_size,
             dir: Direction::Outgoing,
         })
     }
 
-    /// Opens a new connection to [remote], sending a Ping with state [state].
+    /// Opens a new connection to [remote], sending a Ping with states [state] and [primary].
     pub fn open(
-        local: &PeerID,
-        remote: &PeerID,
+        local: &Node,
+        remote: &Endpoint,
         state: &CopyClock,
+        primary: &CopyClock,
+        config: &ConnectionConfig,
     ) -> Result<P2PConnection, Box<Error>> {
         let msg = Message {
             message_id: generate_message_id(),
             message_type: MessageType::Ping {
                 state: state.clone(),
+                primary: Some(primary.clone()),
+                departed: Vec::new(),
             },
-            src_id: local.clone(),
+            src_id: local.id,
+            src_endpoint: local.endpoint,
             ttl: 1,
             hop_count: 0,
         };
 
-        P2PConnection::connect(remote, msg)
+        P2PConnection::connect(remote, msg, config)
+    }
+
+    /// Returns the ID the remote peer authenticated with.
+    pub fn remote_id(&self) -> Result<PeerID, Box<Error>> {
+        PeerID::from_slice(self.conn.remote_static())
+    }
+
+    /// Sets the read and write timeouts of the connection and all its duplicates.
+    /// Reads and writes that time out fail and leave the connection unusable.
+    pub fn set_timeouts(
+        &self,
+        read: time::Duration,
+        write: time::Duration,
+    ) -> Result<(), Box<Error>> {
+        self.conn.set_timeouts(Some(read), Some(write))?;
+        Ok(())
+    }
+
+    /// Returns the protocol version agreed on with the remote peer.
+    pub fn version(&self) -> u32 {
+        self.proto.version
+    }
+
+    /// Returns whether the remote peer opened this connection.
+    pub fn is_incoming(&self) -> bool {
+        self.dir == Direction::Incoming
     }
 
     /// Attempts to duplicate the underlying socket.
@@ -512,93 +1624,746 @@ impl P2PConnection {
         let conn = self.conn.try_clone()?;
         Ok(P2PConnection {
             conn: conn,
+            proto: self.proto,
+            max_message_size: self.max_message_size,
             dir: self.dir.clone(),
         })
     }
 
-    /// Sends a Ping with state [state].
-    pub fn ping(&mut self, state: &CopyClock, local: &Endpoint) -> Result<(), Box<Error>> {
+    /// Sends a Ping with states [state] and [primary], reporting the [departed] peers.
+    pub fn ping(
+        &mut self,
+        state: &CopyClock,
+        primary: &CopyClock,
+        departed: &[PeerID],
+        local: &Node,
+    ) -> Result<(), Box<Error>> {
         let msg = Message {
             message_id: generate_message_id(),
             message_type: MessageType::Ping {
                 state: state.clone(),
+                primary: Some(primary.clone()),
+                departed: departed.to_vec(),
             },
-            src_id: local.clone(),
+            src_id: local.id,
+            src_endpoint: local.endpoint,
             ttl: 1,
             hop_count: 0,
         };
 
-        write_length_prefixed(&mut self.conn, &msg)?;
+        write_length_prefixed(&mut self.conn, self.proto, &msg)?;
 
         Ok(())
     }
 
-    /// Sends a Pong with state [state].
-    pub fn pong(&mut self, state: &CopyClock, local: &Endpoint) -> Result<(), Box<Error>> {
+    /// Sends a Pong with states [state] and [primary], reporting the [departed] peers.
+    pub fn pong(
+        &mut self,
+        state: &CopyClock,
+        primary: &CopyClock,
+        departed: &[PeerID],
+        local: &Node,
+    ) -> Result<(), Box<Error>> {
         let msg = Message {
             message_id: generate_message_id(),
             message_type: MessageType::Pong {
                 state: state.clone(),
+                primary: Some(primary.clone()),
+                departed: departed.to_vec(),
             },
-            src_id: local.clone(),
+            src_id: local.id,
+            src_endpoint: local.endpoint,
             ttl: 1,
             hop_count: 0,
         };
 
-        write_length_prefixed(&mut self.conn, &msg)?;
+        write_length_prefixed(&mut self.conn, self.proto, &msg)?;
 
         Ok(())
     }
 
-    /// Sends a CopyNotification with state [state] and TTL=8.
-    pub fn notify_copy(&mut self, state: &CopyClock, local: &Endpoint) -> Result<(), Box<Error>> {
+    /// Sends a CopyNotification for [selection] with state [state], the [digests] of the content,
+    /// the [content] itself if it is pushed, and TTL=8.
+    /// The notification is sent to every peer with the same [message_id].
+    pub fn notify_copy(
+        &mut self,
+        selection: Selection,
+        state: &CopyClock,
+        digests: &[TargetDigest],
+        content: Option<&ClipboardContent>,
+        message_id: MessageID,
+        local: &Node,
+    ) -> Result<(), Box<Error>> {
         let msg = Message {
-            message_id: generate_message_id(),
+            message_id: message_id,
             message_type: MessageType::CopyNotification {
                 state: state.clone(),
+                selection: selection,
+                digests: digests.to_vec(),
+                content: content.cloned(),
             },
-            src_id: local.clone(),
+            src_id: local.id,
+            src_endpoint: local.endpoint,
             ttl: 8,
             hop_count: 0,
         };
 
-        write_length_prefixed(&mut self.conn, &msg)?;
+        write_length_prefixed(&mut self.conn, self.proto, &msg)?;
 
         Ok(())
     }
 
     /// Forwards a CopyNotification for flooding.
+    /// [message_id] is the ID of the original notification, so that peers can tell they have
+    /// seen it before.
     pub fn forward_notify_copy(
         &mut self,
+        selection: Selection,
         state: &CopyClock,
-        local: &Endpoint,
+        digests: &[TargetDigest],
+        content: Option<&ClipboardContent>,
+        local: &Node,
+        message_id: MessageID,
         ttl: u32,
         hop_count: u32,
     ) -> Result<(), Box<Error>> {
         let msg = Message {
-            message_id: generate_message_id(), // TODO reuse message_id from incoming message
+            message_id: message_id,
             message_type: MessageType::CopyNotification {
                 state: state.clone(),
+                selection: selection,
+                digests: digests.to_vec(),
+                content: content.cloned(),
+            },
+            src_id: local.id,
+            src_endpoint: local.endpoint,
+            ttl: ttl,
+            hop_count: hop_count,
+        };
+
+        write_length_prefixed(&mut self.conn, self.proto, &msg)?;
+
+        Ok(())
+    }
+
+    /// Sends a Leave with [replacements] and [handover].
+    pub fn leave(
+        &mut self,
+        replacements: &[Node],
+        handover: &[Handover],
+        local: &Node,
+    ) -> Result<(), Box<Error>> {
+        let msg = Message {
+            message_id: generate_message_id(),
+            message_type: MessageType::Leave {
+                replacements: replacements.to_vec(),
+                handover: handover.to_vec(),
+            },
+            src_id: local.id,
+            src_endpoint: local.endpoint,
+            ttl: 1,
+            hop_count: 0,
+        };
+
+        write_length_prefixed(&mut self.conn, self.proto, &msg)?;
+
+        Ok(())
+    }
+
+    /// Sends a ReplicaNotification for [replica] with the given [message_id], [ttl] and
+    /// [hop_count], either our own or one we forward.
+    pub fn notify_replica(
+        &mut self,
+        replica: &Replica,
+        local: &Node,
+        message_id: MessageID,
+        ttl: u32,
+        hop_count: u32,
+    ) -> Result<(), Box<Error>> {
+        let msg = Message {
+            message_id: message_id,
+            message_type: MessageType::ReplicaNotification {
+                replica: replica.clone(),
+            },
+            src_id: local.id,
+            src_endpoint: local.endpoint,
+            ttl: ttl,
+            hop_count: hop_count,
+        };
+
+        write_length_prefixed(&mut self.conn, self.proto, &msg)?;
+
+        Ok(())
+    }
+
+    /// Sends a RelayRequest for [selection] held by [source] with the given [message_id], [ttl] and
+    /// [hop_count], either our own or one we forward.
+    pub fn relay_request(
+        &mut self,
+        source: PeerID,
+        selection: Selection,
+        targets: &[String],
+        compression: &[Compression],
+        max_chunk: u32,
+        local: &Node,
+        message_id: MessageID,
+        ttl: u32,
+        hop_count: u32,
+    ) -> Result<(), Box<Error>> {
+        let msg = Message {
+            message_id: message_id,
+            message_type: MessageType::RelayRequest {
+                source: source,
+                selection: selection,
+                targets: targets.to_vec(),
+                compression: compression.to_vec(),
+                max_chunk: max_chunk,
             },
-            src_id: local.clone(),
+            src_id: local.id,
+            src_endpoint: local.endpoint,
             ttl: ttl,
             hop_count: hop_count,
         };
 
-        write_length_prefixed(&mut self.conn, &msg)?;
+        write_length_prefixed(&mut self.conn, self.proto, &msg)?;
+
+        Ok(())
+    }
+
+    /// Sends [response] as part of the answer to the RelayRequest [request], either our own or
+    /// one we forward.
+    pub fn relay_response(
+        &mut self,
+        request: MessageID,
+        response: &RelayAnswer,
+        local: &Node,
+    ) -> Result<(), Box<Error>> {
+        let msg = Message {
+            message_id: generate_message_id(),
+            message_type: MessageType::RelayResponse {
+                request: request,
+                response: response.clone(),
+            },
+            src_id: local.id,
+            src_endpoint: local.endpoint,
+            ttl: 1,
+            hop_count: 0,
+        };
+
+        write_length_prefixed(&mut self.conn, self.proto, &msg)?;
 
         Ok(())
     }
 
     /// Reads a message off the underlying socket.
     pub fn read_message(&mut self) -> Result<Message, Box<Error>> {
-        read_length_prefixed(&mut self.conn)
+        read_length_prefixed(&mut self.conn, self.proto, self.max_message_size)
     }
 
-    /// Flushes and closes the connection.
+    /// Flushes and closes the connection, including all its duplicates, so that a thread reading
+    /// off a duplicate stops.
     pub fn close(mut self) -> Result<(), Box<Error>> {
         self.conn.flush()?;
+        self.conn.shutdown()?;
         Ok(())
-        // conn should be closed as soon as it is dropped, i.e. here
+    }
+}
+
+#[test]
+fn endpoint_serialization() {
+    let v4: Endpoint = serde_json::from_str("\"127.0.0.1:1234\"").unwrap();
+    assert_eq!(serde_json::to_string(&v4).unwrap(), "\"127.0.0.1:1234\"");
+
+    let v6: Endpoint = serde_json::from_str("\"[::1]:1234\"").unwrap();
+    assert_eq!(v6, Endpoint::new(&"::1".parse().unwrap(), 1234));
+    assert_eq!(serde_json::to_string(&v6).unwrap(), "\"[::1]:1234\"");
+
+    // Not an address, and IPv6 without brackets.
+    assert!(serde_json::from_str::<Endpoint>("\"localhost:1234\"").is_err());
+    assert!(serde_json::from_str::<Endpoint>("\"::1:1234\"").is_err());
+}
+
+#[cfg(test)]
+fn test_message(message_type: MessageType) -> Message {
+    Message {
+        message_id: generate_message_id(),
+        message_type: message_type,
+        src_id: PeerID([7; 32]),
+        src_endpoint: Endpoint::new(&"::1".parse().unwrap(), 4242),
+        ttl: 1,
+        hop_count: 0,
+    }
+}
+
+#[test]
+fn codecs_roundtrip() {
+    let msg = test_message(MessageType::BinaryResponse {
+        target: "image/png".to_string(),
+        data: vec![0xff; 1000],
+    });
+
+    for codec in vec![Codec::Json, Codec::Bincode] {
+        let decoded = decode(codec, &encode(codec, &msg).unwrap()).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", msg));
+    }
+    // JSON sends every byte as a number.
+    let json = encode(Codec::Json, &msg).unwrap().len();
+    let bincode = encode(Codec::Bincode, &msg).unwrap().len();
+    assert!(bincode * 2 < json, "bincode: {}, json: {}", bincode, json);
+}
+
+#[test]
+fn protocol_negotiation() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let remote = Endpoint::from(listener.local_addr().unwrap());
+    let config = ConnectionConfig::new(Keys::from_passphrase("test").unwrap());
+    let local = Node {
+        id: config.keys.id(),
+        endpoint: remote,
+    };
+
+    // A current peer negotiates bincode.
+    let client = thread::spawn(move || {
+        let config = ConnectionConfig::new(Keys::from_passphrase("test").unwrap());
+        JoinConnection::open(&local, &remote, 1, &config).unwrap()
+    });
+    match accept(&mut listener, &config).unwrap().conn {
+        Connection::Join(c) => assert_eq!(
+            c.proto,
+            Protocol {
+                version: PROTOCOL_VERSION,
+                codec: Codec::Bincode,
+            }
+        ),
+        c => panic!("expected a JoinConnection, got {:?}", c),
+    }
+    assert_eq!(client.join().unwrap().proto.codec, Codec::Bincode);
+
+    // A legacy peer sends its first message right away.
+    let client = thread::spawn(move || {
+        let keys = Keys::from_passphrase("test").unwrap();
+        let addr = SocketAddr::from((remote.ip, remote.port));
+        let mut stream = SecureStream::connect(addr, &keys).unwrap();
+        let msg = test_message(MessageType::JoinRequest);
+        write_length_prefixed(&mut stream, Protocol::legacy(), &msg).unwrap();
+        stream
+    });
+    match accept(&mut listener, &config).unwrap().conn {
+        Connection::Join(c) => assert_eq!(c.proto, Protocol::legacy()),
+        c => panic!("expected a JoinConnection, got {:?}", c),
+    }
+    client.join().unwrap();
+
+    // A peer of another version gets JSON, even though it prefers bincode.
+    let client = thread::spawn(move || {
+        let keys = Keys::from_passphrase("test").unwrap();
+        let addr = SocketAddr::from((remote.ip, remote.port));
+        let mut stream = SecureStream::connect(addr, &keys).unwrap();
+        let hello = Hello {
+            version: PROTOCOL_VERSION - 1,
+            codecs: vec![Codec::Bincode, Codec::Json],
+        };
+        write_frame(&mut stream, &serde_json::to_vec(&hello).unwrap()).unwrap();
+        let reply: Hello = serde_json::from_slice(&read_frame(&mut stream, 1024).unwrap()).unwrap();
+        let proto = Protocol {
+            version: PROTOCOL_VERSION - 1,
+            codec: reply.codecs[0],
+        };
+        let msg = test_message(MessageType::JoinRequest);
+        write_length_prefixed(&mut stream, proto, &msg).unwrap();
+        (stream, proto)
+    });
+    match accept(&mut listener, &config).unwrap().conn {
+        Connection::Join(c) => assert_eq!(c.proto.codec, Codec::Json),
+        c => panic!("expected a JoinConnection, got {:?}", c),
+    }
+    assert_eq!(client.join().unwrap().1.codec, Codec::Json);
+}
+
+#[test]
+fn legacy_fallback_only_on_close() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let remote = Endpoint::from(listener.local_addr().unwrap());
+    let config = ConnectionConfig::new(Keys::from_passphrase("test").unwrap());
+    let server = thread::spawn(move || {
+        let keys = Keys::from_passphrase("test").unwrap();
+        // Apart from the first one, behave like a legacy peer that drops the connection on
+        // receiving a Hello.
+        for (i, stream) in listener.incoming().take(4).enumerate() {
+            let mut stream = SecureStream::accept(stream.unwrap(), &keys).unwrap();
+            if i == 0 {
+                // Not a legacy peer, just a broken one.
+                read_frame(&mut stream, 1024).unwrap();
+                write_frame(&mut stream, b"garbage").unwrap();
+            }
+        }
+    });
+
+    assert!(open_stream_with(&remote, &config, &config.codecs).is_err());
+    assert!(open_stream_with(&remote, &config, &[Codec::Bincode]).is_err());
+    let (_, proto) = open_stream_with(&remote, &config, &config.codecs).unwrap();
+    assert_eq!(proto, Protocol::legacy());
+    server.join().unwrap();
+}
+
+#[test]
+fn oversized_transfers_are_rejected() {
+    let state = CopyClock::new(
+        &VectorClock::new(),
+        &PeerID([7; 32]),
+        &Endpoint::new(&"::1".parse().unwrap(), 4242),
+    );
+    let target = "text/plain".to_string();
+    let too_large = MAX_CONTENT_SIZE + 1;
+    let none = Compression::None;
+    assert!(Transfer::new(target.clone(), state.clone(), none, too_large, too_large).is_err());
+    let deflate = Compression::Deflate;
+    assert!(Transfer::new(target.clone(), state.clone(), deflate, too_large, 100).is_err());
+
+    let mut transfer = Transfer::new(target, state, Compression::None, 100, 100).unwrap();
+    transfer.push(&[0u8; 60]).unwrap();
+    assert!(transfer.push(&[0u8; 60]).is_err());
+    transfer.push(&[0u8; 40]).unwrap();
+    assert!(transfer.is_complete());
+}
+
+#[test]
+fn pushed_notifications_fit_into_a_message() {
+    let mut config = ConnectionConfig::new(Keys::from_passphrase("test").unwrap());
+    config.max_message_size = 64 * 1024;
+    config.push_size = 1024 * 1024;
+    assert_eq!(config.max_push_size(), 8 * 1024);
+
+    let id = config.keys.id();
+    let endpoint = Endpoint::new(&"::1".parse().unwrap(), 4242);
+    let mut content = ClipboardContent::new();
+    content.insert("text/plain", vec![255u8; config.max_push_size()]);
+    let msg = Message {
+        message_id: generate_message_id(),
+        message_type: MessageType::CopyNotification {
+            state: CopyClock::new(&VectorClock::new().incr_clone(id), &id, &endpoint),
+            selection: Selection::Clipboard,
+            digests: TargetDigest::of_content(&content),
+            content: Some(content),
+        },
+        src_id: id,
+        src_endpoint: endpoint,
+        ttl: 8,
+        hop_count: 0,
+    };
+    for &codec in &[Codec::Json, Codec::Bincode] {
+        assert!(encode(codec, &msg).unwrap().len() <= config.max_message_size);
+    }
+}
+
+#[test]
+fn oversized_frames_are_rejected() {
+    let mut buf = Vec::new();
+    write_frame(&mut buf, &[0u8; 100]).unwrap();
+
+    assert!(read_frame(&mut &buf[..], 99).is_err());
+    assert_eq!(read_frame(&mut &buf[..], 100).unwrap().len(), 100);
+}
+
+#[test]
+fn streaming_resumes_at_offset() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let remote = Endpoint::from(listener.local_addr().unwrap());
+    let mut config = ConnectionConfig::new(Keys::from_passphrase("test").unwrap());
+    // Small messages make for many chunks.
+    config.max_message_size = 8 * 1024;
+    let local = Node {
+        id: config.keys.id(),
+        endpoint: remote,
+    };
+    let state = CopyClock::new(&VectorClock::new(), &local.id, &remote);
+    // Not compressible.
+    let data: Vec<u8> = (0..100000).map(|_| rand::random()).collect();
+    let target = "image/png".to_string();
+
+    let server_data = data.clone();
+    let server_state = state.clone();
+    let server = thread::spawn(move || {
+        let config = ConnectionConfig::new(Keys::from_passphrase("test").unwrap());
+        for _ in 0..2 {
+            let incoming = accept(&mut listener, &config).unwrap();
+            let (offset, max_chunk) = match incoming.first_msg.message_type {
+                MessageType::StreamRequest {
+                    offset, max_chunk, ..
+                } => (offset, max_chunk),
+                t => panic!("expected a StreamRequest, got {:?}", t),
+            };
+            match incoming.conn {
+                Connection::Copy(mut c) => {
+                    let (len, wire_len) = c.respond_stream(
+                        &"image/png".to_string(),
+                        &server_data,
+                        offset,
+                        max_chunk,
+                        &[Compression::Deflate],
+                        &server_state,
+                        &local,
+                    ).unwrap();
+                    // Random data does not compress, so it is sent as-is.
+                    assert_eq!(len, 100000);
+                    assert_eq!(wire_len, len - offset);
+                }
+                c => panic!("expected a CopyConnection, got {:?}", c),
+            }
+        }
+    });
+
+    let targets = vec![target.clone()];
+    let mut transfer = None;
+    let mut c =
+        CopyConnection::open(&local, &remote, Selection::Clipboard, &targets, 0, &config).unwrap();
+    let header = c.read_message().unwrap();
+    let mut calls = 0;
+    c.read_stream(header, &mut transfer, &mut |_, total| {
+        assert_eq!(total, 100000);
+        calls += 1;
+    })
+    .unwrap();
+    assert!(calls > 10, "only {} progress reports", calls);
+    assert_eq!(transfer.as_ref().unwrap().data, data);
+
+    // Pretend the connection broke after 12345 bytes.
+    transfer.as_mut().unwrap().data.truncate(12345);
+    let mut c = CopyConnection::open(
+        &local,
+        &remote,
+        Selection::Clipboard,
+        &targets,
+        12345,
+        &config,
+    )
+    .unwrap();
+    let header = c.read_message().unwrap();
+    c.read_stream(header, &mut transfer, &mut |_, _| ())
+        .unwrap();
+    let transfer = transfer.unwrap();
+    assert_eq!(transfer.target, target);
+    assert_eq!(transfer.state, state);
+    assert_eq!(transfer.content().unwrap(), data);
+
+    server.join().unwrap();
+}
+
+#[test]
+fn compression_roundtrip() {
+    let log = "INFO paste-anywhere: nothing happened\n".repeat(1000).into_bytes();
+
+    let compressed = compress(Compression::Deflate, &log).unwrap();
+    assert!(compressed.len() * 10 < log.len());
+    assert_eq!(
+        decompress(Compression::Deflate, compressed.clone(), log.len() as u64).unwrap(),
+        log
+    );
+    // The announced length limits the output.
+    assert!(decompress(Compression::Deflate, compressed.clone(), 100).is_err());
+    assert!(decompress(Compression::Deflate, compressed, MAX_CONTENT_SIZE + 1).is_err());
+}
+
+#[test]
+fn target_digests_match() {
+    let mut content = ClipboardContent::from_text("hello");
+    content.insert("image/png", vec![0x89, 0x50, 0x4e, 0x47]);
+    let digests = TargetDigest::of_content(&content);
+    assert_eq!(digests.len(), 2);
+
+    for d in digests.iter() {
+        assert!(d.matches(content.get(&d.target).unwrap()));
+        assert!(!d.matches(b"hellO"));
+    }
+    // The same data in another target is different content.
+    assert_ne!(
+        ContentDigest::of("text/plain", b"hello"),
+        ContentDigest::of("text/html", b"hello")
+    );
+}
+
+#[test]
+fn relayed_stream_roundtrip() {
+    let log = "INFO paste-anywhere: nothing happened\n".repeat(1000).into_bytes();
+    let state = CopyClock::new(
+        &VectorClock::new(),
+        &PeerID([7; 32]),
+        &Endpoint::new(&"::1".parse().unwrap(), 4242),
+    );
+    let target = "text/plain".to_string();
+    let messages =
+        stream_messages(&target, &log, 100, &[Compression::Deflate], &state).unwrap();
+
+    let mut messages = messages.into_iter();
+    let mut transfer = match messages.next() {
+        Some(RelayAnswer::StreamHeader {
+            target,
+            state,
+            compression,
+            len,
+            total_len,
+            offset: 0,
+        }) => Transfer::new(target, state, compression, len, total_len).unwrap(),
+        m => panic!("expected a StreamHeader, got {:?}", m),
+    };
+    assert_eq!(transfer.compression, Compression::Deflate);
+    for m in messages {
+        // Every hop forwards the messages wrapped in RelayResponses.
+        let msg = test_message(MessageType::RelayResponse {
+            request: generate_message_id(),
+            response: m,
+        });
+        let msg = decode(Codec::Bincode, &encode(Codec::Bincode, &msg).unwrap()).unwrap();
+        match msg.message_type {
+            MessageType::RelayResponse { response, .. } => match response {
+                RelayAnswer::Chunk { data } => {
+                    assert!(data.len() <= 100);
+                    transfer.push(&data).unwrap();
+                }
+                m => panic!("expected a Chunk, got {:?}", m),
+            },
+            m => panic!("expected a RelayResponse, got {:?}", m),
+        }
+    }
+    assert_eq!(transfer.content().unwrap(), log);
+
+    // Parts of the answer are encoded like the messages of older peers.
+    let error = RelayAnswer::ErrorResponse {
+        state: state.clone(),
+        error: "gone".to_string(),
+    };
+    let json = serde_json::to_vec(&MessageType::ErrorResponse {
+        state: state,
+        error: "gone".to_string(),
+    }).unwrap();
+    assert_eq!(serde_json::to_vec(&error).unwrap(), json);
+    // Other messages can't be smuggled into an answer.
+    let leave = serde_json::to_vec(&MessageType::Leave {
+        replacements: Vec::new(),
+        handover: Vec::new(),
+    }).unwrap();
+    assert!(serde_json::from_slice::<RelayAnswer>(&leave).is_err());
+}
+
+#[test]
+fn copy_clocks_order_totally() {
+    let endpoint = Endpoint::new(&"::1".parse().unwrap(), 4242);
+    let clock = VectorClock::new().incr_clone(PeerID([1; 32]));
+    let a = CopyClock::new(&clock, &PeerID([1; 32]), &endpoint);
+    // The same events, but a different source, e.g. after a handover.
+    let b = CopyClock::new(&clock, &PeerID([2; 32]), &endpoint);
+    let c = CopyClock::new(&clock.incr_clone(PeerID([2; 32])), &PeerID([2; 32]), &endpoint);
+
+    assert_eq!(a.total_cmp(&a), cmp::Ordering::Equal);
+    assert_eq!(a.total_cmp(&b), cmp::Ordering::Less);
+    assert_eq!(b.total_cmp(&a), cmp::Ordering::Greater);
+    // The clock decides first.
+    assert_eq!(b.total_cmp(&c), cmp::Ordering::Less);
+    assert_eq!(a.total_cmp(&c), cmp::Ordering::Less);
+}
+
+#[test]
+fn latest_copy_wins() {
+    let endpoint = Endpoint::new(&"::1".parse().unwrap(), 4242);
+    let (a, b) = (PeerID([1; 32]), PeerID([2; 32]));
+    let base = VectorClock::new();
+    let mut early = CopyClock::new(&base.incr_clone(b), &b, &endpoint);
+    early.time = HybridTimestamp {
+        time: 1000,
+        counter: 0,
+    };
+    let mut late = CopyClock::new(&base.incr_clone(a), &a, &endpoint);
+    late.time = HybridTimestamp {
+        time: 2000,
+        counter: 0,
+    };
+
+    // Concurrent copies, the clocks prefer the early one.
+    assert_eq!(late.resolve(&early, ConflictPolicy::Clock), cmp::Ordering::Less);
+    assert_eq!(late.resolve(&early, ConflictPolicy::Latest), cmp::Ordering::Greater);
+    assert_eq!(early.resolve(&late, ConflictPolicy::Latest), cmp::Ordering::Less);
+
+    // A copy after the late one observed its timestamp, so it wins even with a wall-clock behind.
+    let mut after = CopyClock::new(&late.clock.incr_clone(b), &b, &endpoint);
+    after.time = HybridTimestamp {
+        time: 2000,
+        counter: 1,
+    };
+    assert_eq!(after.resolve(&late, ConflictPolicy::Latest), cmp::Ordering::Greater);
+
+    // Of concurrent copies, the one that knew of more copies before still loses on wall time.
+    let c = PeerID([3; 32]);
+    let mut busy = CopyClock::new(&after.clock.incr_clone(a).incr_clone(b), &b, &endpoint);
+    busy.time = HybridTimestamp {
+        time: 2500,
+        counter: 0,
+    };
+    let mut later = CopyClock::new(&base.incr_clone(c), &c, &endpoint);
+    later.time = HybridTimestamp {
+        time: 3000,
+        counter: 0,
+    };
+    assert!(busy.clock.events() > later.clock.events());
+    assert_eq!(busy.resolve(&later, ConflictPolicy::Clock), cmp::Ordering::Greater);
+    assert_eq!(busy.resolve(&later, ConflictPolicy::Latest), cmp::Ordering::Less);
+    assert_eq!(later.resolve(&busy, ConflictPolicy::Latest), cmp::Ordering::Greater);
+}
+
+#[test]
+fn latest_order_is_transitive() {
+    use clock::TemporalRelation;
+
+    let endpoint = Endpoint::new(&"::1".parse().unwrap(), 4242);
+    let (a, b, c) = (PeerID([1; 32]), PeerID([2; 32]), PeerID([3; 32]));
+    let base = VectorClock::new();
+    let mut folded = base.incr_clone(a).incr_clone(c);
+    folded.fold(|id| *id != c);
+    let clocks = vec![
+        (base.incr_clone(a), a),
+        (base.incr_clone(b), b),
+        (base.incr_clone(c), c),
+        (base.incr_clone(a).incr_clone(b), b),
+        (base.incr_clone(b).incr_clone(c), c),
+        (folded.incr_clone(b), b),
+    ];
+    // Copies by older peers carry no timestamp, mixed with timestamped ones that disagree with
+    // the clocks.
+    let mut states = Vec::new();
+    for &(ref clock, src) in &clocks {
+        for &time in &[0, 1000, 2000] {
+            let mut state = CopyClock::new(clock, &src, &endpoint);
+            state.time = HybridTimestamp {
+                time: time,
+                counter: 0,
+            };
+            states.push(state);
+        }
+    }
+
+    let latest = ConflictPolicy::Latest;
+    for x in &states {
+        for y in &states {
+            assert_eq!(x.resolve(y, latest), y.resolve(x, latest).reverse());
+            // As long as the timestamps follow causality, as those of a HybridClock do.
+            if x.clock.temporal_relation(&y.clock) == TemporalRelation::Caused && x.time < y.time {
+                assert_eq!(x.resolve(y, latest), cmp::Ordering::Less);
+            }
+            for z in &states {
+                if x.resolve(y, latest) != cmp::Ordering::Greater
+                    && y.resolve(z, latest) != cmp::Ordering::Greater
+                {
+                    assert_ne!(x.resolve(z, latest), cmp::Ordering::Greater);
+                }
+            }
+        }
     }
 }
@@ -1,72 +1,832 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::net::TcpListener;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv6Addr};
+use std::io;
 use std::error::Error;
 use std::thread;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::mpsc::*;
 use std::time;
+use std::cmp;
 
+use net2::TcpBuilder;
 use rand;
 use rand::Rng;
 
-use clock::VectorClock;
+use clock::{HybridClock, VectorClock};
 use clock::TemporalRelation;
 use network::*;
+use clipboard::{ClipboardContent, Selection};
+use seen::{SeenCache, SeenStats};
+
+/// The number of peers to stay connected to at least, by default.
+const DEFAULT_MIN_DEGREE: usize = 3;
+
+/// The number of peers to stay connected to at most, by default.
+const DEFAULT_MAX_DEGREE: usize = 8;
+
+/// How often the degree is checked, in seconds.
+const MAINTENANCE_INTERVAL_SECS: u64 = 30;
+
+/// How often the accepting thread checks whether it should stop, in milliseconds.
+const ACCEPT_POLL_INTERVAL_MS: u64 = 100;
+
+/// How many replacement peers are suggested to every peer when leaving.
+const MAX_REPLACEMENTS: usize = 3;
+
+/// How often every peer is pinged, in seconds.
+const PING_INTERVAL_SECS: u64 = 10;
+
+/// After how many unanswered Pings in a row a peer is considered dead and removed.
+const MAX_MISSED_PONGS: u32 = 3;
+
+/// How long a write to a peer may block before the connection is considered dead, in seconds.
+const PEER_WRITE_TIMEOUT_SECS: u64 = 10;
+
+/// How many messages may wait to be sent to a peer, e.g. the chunks of a relayed clipboard.
+const PEER_SEND_QUEUE: usize = 64;
+
+/// How many JoinRequest IDs are remembered to not forward a JoinRequest twice.
+const SEEN_JOIN_REQUESTS: usize = 4096;
+
+/// How long a JoinRequest ID is remembered, in seconds.
+const SEEN_JOIN_REQUESTS_SECS: u64 = 60;
+
+/// How many CopyNotification IDs are remembered to not forward a notification twice.
+const SEEN_COPY_NOTIFICATIONS: usize = 4096;
+
+/// How long a CopyNotification ID is remembered, in seconds.
+/// This only needs to outlast the flood, which is limited by the TTL.
+const SEEN_COPY_NOTIFICATIONS_SECS: u64 = 60;
+
+/// How often a streamed clipboard transfer is attempted before giving up.
+const MAX_TRANSFER_ATTEMPTS: u32 = 3;
+
+/// How many holders of replicas of the latest content are remembered per selection.
+const MAX_REPLICAS: usize = 8;
+
+/// The TTL of our own ReplicaNotifications, the same as that of CopyNotifications.
+const REPLICA_NOTIFICATION_TTL: u32 = 8;
+
+/// The TTL of our own RelayRequests, the same as that of CopyNotifications.
+const RELAY_REQUEST_TTL: u32 = 8;
+
+/// How long to wait for the next part of the answer to a RelayRequest, in seconds.
+const RELAY_TIMEOUT_SECS: u64 = 5;
+
+/// How long to remember where a forwarded RelayRequest came from, in seconds.
+const RELAY_ROUTE_SECS: u64 = 60;
+
+/// How long a part of a relayed answer waits for room in the queue of the next peer before the
+/// relay is given up, in seconds. Shorter than `RELAY_TIMEOUT_SECS`, so that the requester
+/// learns why.
+const RELAY_FORWARD_SECS: u64 = 2;
+
+/// How long a leaving peer waits for its handover to be fetched, in seconds.
+const HANDOVER_TIMEOUT_SECS: u64 = 10;
 
 /// An enum used to determine the type of message to be sent on a P2PConnection.
 #[derive(Clone, Debug)]
 enum P2PSend {
-    Ping(CopyClock),
-    CopyNotification(CopyClock),
-    ForwardCopyNotification(CopyClock, u32, u32),
+    Ping(CopyClock, CopyClock, Vec<PeerID>),
+    CopyNotification(
+        Selection,
+        CopyClock,
+        Vec<TargetDigest>,
+        Option<ClipboardContent>,
+        MessageID,
+    ),
+    ForwardCopyNotification(
+        Selection,
+        CopyClock,
+        Vec<TargetDigest>,
+        Option<ClipboardContent>,
+        MessageID,
+        u32,
+        u32,
+    ),
+    Leave(Vec<Node>, Vec<Handover>, Sender<Result<(), String>>),
+    ReplicaNotification(Replica, MessageID, u32, u32),
+    RelayRequest(
+        PeerID,
+        Selection,
+        Vec<String>,
+        Vec<Compression>,
+        u32,
+        MessageID,
+        u32,
+        u32,
+    ),
+    RelayResponse(MessageID, RelayAnswer),
+}
+
+/// The state kept for one selection.
+/// Each selection is synchronized independently and has its own CopyClock.
+struct SelectionState {
+    // TODO maybe have state, clipboard in one mutex for more safety
+    state: Mutex<CopyClock>,
+    clipboard: Mutex<ClipboardContent>,
+    cached_clipboard: Mutex<ClipboardContent>,
+    cache_state: Mutex<CopyClock>,
+    /// Content taken over from a leaving peer that was not written to the local clipboard yet.
+    taken_over: Mutex<Option<ClipboardContent>>,
+    /// Peers that announced to have the content at the current state.
+    replicas: Mutex<Vec<Replica>>,
+    /// The digests of the content at a state, as announced by the peer that copied it.
+    digests: Mutex<Option<(CopyClock, Vec<TargetDigest>)>>,
+}
+
+impl SelectionState {
+    fn new(own: &Node) -> SelectionState {
+        SelectionState {
+            state: Mutex::new(CopyClock::new(&VectorClock::new(), &own.id, &own.endpoint)),
+            clipboard: Mutex::new(ClipboardContent::new()),
+            cached_clipboard: Mutex::new(ClipboardContent::new()),
+            cache_state: Mutex::new(CopyClock::new(&VectorClock::new(), &own.id, &own.endpoint)),
+            taken_over: Mutex::new(None),
+            replicas: Mutex::new(Vec::new()),
+            digests: Mutex::new(None),
+        }
+    }
+
+    /// Returns the digests announced for the content at [state], if any.
+    fn digests_of(&self, state: &CopyClock) -> Vec<TargetDigest> {
+        match *self.digests.lock().unwrap() {
+            Some((ref announced, ref digests)) if announced == state => digests.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Caches [content] pushed along with the CopyNotification for [state], if it is what the
+    /// announced [digests] describe.
+    fn cache_pushed(
+        &self,
+        state: &CopyClock,
+        digests: &[TargetDigest],
+        content: &ClipboardContent,
+    ) {
+        if content.targets().len() != digests.len() {
+            println!("peer: pushed content does not match the announced digests, ignoring");
+            return;
+        }
+        if let Err(e) = verify_digests(content, digests) {
+            println!("peer: pushed content is invalid, ignoring: {}", e);
+            return;
+        }
+        let mut cached = self.cached_clipboard.lock().unwrap();
+        *cached = content.clone();
+        *self.cache_state.lock().unwrap() = state.clone();
+        println!("peer: cached pushed content of {} bytes", content.len());
+    }
+
+    /// Returns content we already hold locally, either fetched or copied ourselves, in those of
+    /// [targets] whose data matches the [digests] announced for them.
+    /// Nothing is returned unless the most preferred of [targets] the digests describe matches.
+    fn find_local(&self, targets: &[String], digests: &[TargetDigest]) -> Option<ClipboardContent> {
+        let mut found = ClipboardContent::new();
+        let described = targets
+            .iter()
+            .filter_map(|t| digests.iter().find(|d| d.target == *t));
+        for (i, digest) in described.enumerate() {
+            for candidate in &[&self.cached_clipboard, &self.clipboard] {
+                let content = candidate.lock().unwrap();
+                if let Some(data) = content.get(&digest.target) {
+                    if digest.matches(data) {
+                        found.insert(&digest.target, data.to_vec());
+                        break;
+                    }
+                }
+            }
+            if i == 0 && found.is_empty() {
+                return None;
+            }
+        }
+        if found.is_empty() {
+            return None;
+        }
+        Some(found)
+    }
+
+    /// Returns the content we can give to other peers and the state it belongs to: our own
+    /// clipboard if we copied last, or a replica of the latest one.
+    /// If we have neither, the current state is returned as the error.
+    fn servable(&self, own: &Node) -> Result<(ClipboardContent, CopyClock), CopyClock> {
+        let state = self.state.lock().unwrap().clone();
+        if state.last_copy_src.eq(&own.id) {
+            // set_clipboard updates the state while holding the clipboard, so this state
+            // matches the content. Resumed transfers rely on that.
+            let clipboard = self.clipboard.lock().unwrap();
+            return Ok((clipboard.clone(), self.state.lock().unwrap().clone()));
+        }
+        // Someone who can't reach the source may ask for our replica.
+        let cached = self.cached_clipboard.lock().unwrap();
+        let cache_state = self.cache_state.lock().unwrap().clone();
+        if cache_state != state || cached.is_empty() {
+            return Err(state);
+        }
+        Ok((cached.clone(), cache_state))
+    }
+
+    /// Remembers [replica] as a source of the content at its state, forgetting the replicas of
+    /// other states.
+    fn add_replica(&self, replica: Replica) {
+        let mut replicas = self.replicas.lock().unwrap();
+        replicas.retain(|r| r.state == replica.state && r.holder.id != replica.holder.id);
+        if replicas.len() < MAX_REPLICAS {
+            replicas.push(replica);
+        }
+    }
+}
+
+/// Relays keeps track of the RelayRequests passing through this node, so that their answers can
+/// be routed back, and of our own RelayRequests waiting for an answer.
+struct Relays {
+    /// The peer every forwarded RelayRequest came from, the selection it asks for, and when.
+    routes: Mutex<HashMap<MessageID, (PeerID, Selection, time::Instant)>>,
+    waiting: Mutex<HashMap<MessageID, Sender<RelayAnswer>>>,
+}
+
+impl Relays {
+    fn new() -> Relays {
+        Relays {
+            routes: Mutex::new(HashMap::new()),
+            waiting: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remembers that the RelayRequest [request] for [selection] came from [from], forgetting
+    /// routes older than `RELAY_ROUTE_SECS`.
+    fn add_route(&self, request: MessageID, from: PeerID, selection: Selection) {
+        let now = time::Instant::now();
+        let max_age = time::Duration::from_secs(RELAY_ROUTE_SECS);
+        let mut routes = self.routes.lock().unwrap();
+        routes.retain(|_, &mut (_, _, added)| now.duration_since(added) < max_age);
+        routes.insert(request, (from, selection, now));
+    }
+
+    /// Returns the peer the RelayRequest [request] came from and the selection it asks for.
+    fn route(&self, request: &MessageID) -> Option<(PeerID, Selection)> {
+        self.routes
+            .lock()
+            .unwrap()
+            .get(request)
+            .map(|&(from, selection, _)| (from, selection))
+    }
+
+    /// Gives up relaying the answer to [request], the rest of it is dropped.
+    fn abandon(&self, request: &MessageID) {
+        self.routes.lock().unwrap().remove(request);
+    }
+
+    /// Registers our own RelayRequest [request], returning where its answer arrives.
+    fn wait(&self, request: MessageID) -> Receiver<RelayAnswer> {
+        let (tx, rx) = channel();
+        self.waiting.lock().unwrap().insert(request, tx);
+        rx
+    }
+
+    /// Hands [response] to whoever waits for the answer to our own RelayRequest [request].
+    /// Returns false if nobody does.
+    fn deliver(&self, request: &MessageID, response: RelayAnswer) -> bool {
+        match self.waiting.lock().unwrap().get(request) {
+            Some(tx) => tx.send(response).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Stops waiting for the answer to our own RelayRequest [request].
+    fn done(&self, request: &MessageID) {
+        self.waiting.lock().unwrap().remove(request);
+    }
+}
+
+/// Queues [msg] on [sender], waiting at most [timeout] for room in its queue.
+fn send_within(
+    sender: &SyncSender<P2PSend>,
+    msg: P2PSend,
+    timeout: time::Duration,
+) -> Result<(), String> {
+    let deadline = time::Instant::now() + timeout;
+    let mut msg = msg;
+    loop {
+        match sender.try_send(msg) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Disconnected(_)) => return Err("peer is gone".to_string()),
+            Err(TrySendError::Full(m)) => {
+                if time::Instant::now() >= deadline {
+                    return Err(format!("peer didn't catch up within {:?}", timeout));
+                }
+                msg = m;
+                thread::sleep(time::Duration::from_millis(10));
+            }
+        }
+    }
+}
+
+/// Returns the answer to a RelayRequest for [selection_state] in one of [targets]: a
+/// StreamHeader followed by Chunks of at most [max_chunk] bytes, or an ErrorResponse.
+fn relay_responses(
+    own: &Node,
+    selection_state: &SelectionState,
+    targets: &[String],
+    max_chunk: u32,
+    compression: &[Compression],
+) -> Vec<RelayAnswer> {
+    let error = |error: &str, state: CopyClock| {
+        vec![RelayAnswer::ErrorResponse {
+            state: state,
+            error: error.to_string(),
+        }]
+    };
+    let (clipboard, state) = match selection_state.servable(own) {
+        Ok(servable) => servable,
+        Err(state) => {
+            println!("<-relay: I don't have the latest clipboard, replying error");
+            return error("I don't have the latest clipboard", state);
+        }
+    };
+    let target = match clipboard.best_target(targets) {
+        Some(target) => target,
+        None => {
+            println!(
+                "<-relay: none of {:?} available (have {:?}), replying error",
+                targets,
+                clipboard.targets()
+            );
+            return error("none of the requested targets are available", state);
+        }
+    };
+    println!("<-relay: streaming target {}...", target);
+    let data = clipboard.get(&target).unwrap();
+    match stream_messages(&target, data, max_chunk, compression, &state) {
+        Ok(messages) => messages,
+        Err(e) => error(&format!("unable to stream: {}", e), state),
+    }
+}
+
+/// TransferStats count the clipboard content exchanged with other peers, before and after
+/// compression.
+#[derive(Debug, Clone, Default)]
+pub struct TransferStats {
+    pub sent: u64,
+    pub sent_wire: u64,
+    pub received: u64,
+    pub received_wire: u64,
+}
+
+impl TransferStats {
+    /// Returns how many bytes of content were transferred per byte sent over the wire.
+    pub fn compression_ratio(&self) -> f64 {
+        let wire = self.sent_wire + self.received_wire;
+        if wire == 0 {
+            return 1.0;
+        }
+        (self.sent + self.received) as f64 / wire as f64
+    }
+}
+
+/// The SelectionStates for CLIPBOARD and PRIMARY.
+struct Selections {
+    clipboard: SelectionState,
+    primary: SelectionState,
+    /// Timestamps our copies, for both selections.
+    hlc: Mutex<HybridClock>,
+    /// The peers that left or were evicted for not answering. Their clock entries are folded
+    /// once all our peers report them as departed too.
+    /// A peer is removed again when it reconnects or copies.
+    departed: Mutex<HashSet<PeerID>>,
+    /// Decides between concurrent copies.
+    policy: ConflictPolicy,
+}
+
+impl Selections {
+    fn new(own: &Node, policy: ConflictPolicy) -> Selections {
+        Selections {
+            clipboard: SelectionState::new(own),
+            primary: SelectionState::new(own),
+            hlc: Mutex::new(HybridClock::new()),
+            departed: Mutex::new(HashSet::new()),
+            policy: policy,
+        }
+    }
+
+    fn get(&self, selection: Selection) -> &SelectionState {
+        match selection {
+            Selection::Clipboard => &self.clipboard,
+            Selection::Primary => &self.primary,
+        }
+    }
+
+    /// Returns copies of the current states for CLIPBOARD and PRIMARY, for pings and pongs.
+    fn states(&self) -> (CopyClock, CopyClock) {
+        (
+            self.clipboard.state.lock().unwrap().clone(),
+            self.primary.state.lock().unwrap().clone(),
+        )
+    }
+
+    /// Returns the peers we consider departed, for pings and pongs.
+    fn departed_peers(&self) -> Vec<PeerID> {
+        self.departed.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Updates both states from a Ping or Pong, returning the updated states.
+    /// [primary] is None for peers that don't synchronize PRIMARY.
+    fn update(&self, state: CopyClock, primary: Option<CopyClock>) -> (CopyClock, CopyClock) {
+        let state = update_state(&self.clipboard.state, state, self.policy);
+        let primary = match primary {
+            Some(primary) => update_state(&self.primary.state, primary, self.policy),
+            None => self.primary.state.lock().unwrap().clone(),
+        };
+        (state, primary)
+    }
 }
 
 /// Updates the given state with the given [new_state], returning the updated state.
 /// The updated clock will be newer or equal to both clocks.
-fn update_state(overlay_state: Arc<Mutex<CopyClock>>, new_state: CopyClock) -> CopyClock {
+/// States are ordered by `CopyClock::resolve` with [policy], so if two peers copied concurrently,
+/// every node settles on the same one of them.
+fn update_state(
+    overlay_state: &Mutex<CopyClock>,
+    new_state: CopyClock,
+    policy: ConflictPolicy,
+) -> CopyClock {
     let mut overlay_state = overlay_state.lock().unwrap();
-    let ord = overlay_state.clock.temporal_relation(&new_state.clock);
+    match overlay_state.clock.temporal_relation(&new_state.clock) {
+        TemporalRelation::ConcurrentGreater | TemporalRelation::ConcurrentSmaller => println!(
+            "overlay: resolving concurrent states {:?} and {:?}",
+            *overlay_state, new_state
+        ),
+        _ => (),
+    }
 
-    match ord {
-        TemporalRelation::Equal => overlay_state.clone(),
-        TemporalRelation::EffectOf => overlay_state.clone(),
-        TemporalRelation::Caused => {
-            *overlay_state = new_state;
-            overlay_state.clone()
+    if overlay_state.resolve(&new_state, policy) == cmp::Ordering::Less {
+        *overlay_state = new_state;
+    }
+    overlay_state.clone()
+}
+
+/// Receives the answer to one of our own RelayRequests for [selection] at [expected] from
+/// [responses], as a complete Transfer.
+/// If the answer is an error or for another state, the state it carries is merged into our own.
+fn receive_transfer(
+    selections: &Selections,
+    selection: Selection,
+    expected: &CopyClock,
+    responses: &Receiver<RelayAnswer>,
+) -> Result<Transfer, Box<Error>> {
+    let next = || {
+        responses
+            .recv_timeout(time::Duration::from_secs(RELAY_TIMEOUT_SECS))
+            .map_err(|e| format!("no answer: {}", e))
+    };
+
+    let mut transfer = match next()? {
+        RelayAnswer::ErrorResponse { state, error } => {
+            println!(
+                "->relay: received error response, err: {}, state: {:?}",
+                error, state
+            );
+            let new_state =
+                update_state(&selections.get(selection).state, state, selections.policy);
+            println!("->relay: updated own state to {:?}", new_state);
+            return Err(From::from(format!("remote replied with error: {}", error)));
+        }
+        RelayAnswer::StreamHeader {
+            target,
+            state,
+            compression,
+            len,
+            total_len,
+            offset: 0,
+        } => {
+            if state != *expected {
+                // Stale, or the source moved on. Either way this is not the content we want.
+                let new_state = update_state(
+                    &selections.get(selection).state,
+                    state.clone(),
+                    selections.policy,
+                );
+                println!("->relay: updated own state to {:?}", new_state);
+                return Err(From::from(format!(
+                    "answer is at state {:?}, not {:?}",
+                    state, expected
+                )));
+            }
+            Transfer::new(target, state, compression, len, total_len)?
         }
-        TemporalRelation::ConcurrentGreater => overlay_state.clone(),
-        TemporalRelation::ConcurrentSmaller => {
-            *overlay_state = new_state;
-            overlay_state.clone()
+        t => return Err(From::from(format!("expected a StreamHeader, got {:?}", t))),
+    };
+    println!(
+        "->relay: receiving {} bytes of {}",
+        transfer.total_len, transfer.target
+    );
+    while !transfer.is_complete() {
+        match next()? {
+            RelayAnswer::Chunk { data } => transfer.push(&data)?,
+            RelayAnswer::ErrorResponse { error, .. } => {
+                return Err(From::from(format!("relay was given up: {}", error)))
+            }
+            t => return Err(From::from(format!("expected a Chunk, got {:?}", t))),
+        }
+    }
+    Ok(transfer)
+}
+
+/// Fetches every target of [handover] from the leaving peer [remote_id] with RelayRequests sent
+/// through [sender], the connection to it, and checks them against the handed over digests.
+fn fetch_handover(
+    remote_id: PeerID,
+    handover: &Handover,
+    sender: &SyncSender<P2PSend>,
+    selections: &Selections,
+    seen_copy_notifications: &Mutex<SeenCache<MessageID>>,
+    relays: &Relays,
+    config: &ConnectionConfig,
+) -> Result<ClipboardContent, Box<Error>> {
+    let mut content = ClipboardContent::new();
+    for digest in &handover.digests {
+        let message_id = generate_message_id();
+        seen_copy_notifications.lock().unwrap().insert(message_id);
+        let responses = relays.wait(message_id);
+        let resp = sender
+            .send(P2PSend::RelayRequest(
+                remote_id,
+                handover.selection,
+                vec![digest.target.clone()],
+                config.compression.clone(),
+                config.max_chunk(),
+                message_id,
+                1,
+                0,
+            ))
+            .map_err(From::from)
+            .and_then(|_| {
+                receive_transfer(selections, handover.selection, &handover.state, &responses)
+            });
+        relays.done(&message_id);
+        let transfer = resp?;
+        if transfer.target != digest.target {
+            return Err(From::from(format!(
+                "asked for {}, got {}",
+                digest.target, transfer.target
+            )));
+        }
+        let data = transfer.content()?;
+        if !digest.matches(&data) {
+            return Err(From::from(format!(
+                "{} bytes of {} don't match the handed over {} bytes with {:?}",
+                data.len(),
+                digest.target,
+                digest.len,
+                digest.digest
+            )));
+        }
+        content.insert(&digest.target, data);
+    }
+    Ok(content)
+}
+
+/// Checks the data of every target of [content] against the [digests] announced for it.
+fn verify_digests(content: &ClipboardContent, digests: &[TargetDigest]) -> Result<(), Box<Error>> {
+    for d in digests {
+        if let Some(data) = content.get(&d.target) {
+            if !d.matches(data) {
+                return Err(From::from(format!(
+                    "{} bytes of {} don't match the announced {} bytes with {:?}",
+                    data.len(),
+                    d.target,
+                    d.len,
+                    d.digest
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the departed peers whose clock entries may be folded: those that we and all our
+/// [peers] consider departed.
+/// Nothing is folded while one of the [peers] predates `FOLDING_VERSION`, or without peers.
+fn foldable(selections: &Selections, peers: &HashMap<PeerID, Peer>) -> HashSet<PeerID> {
+    // Older peers ignore the base counter, so they would lose the folded entries.
+    if peers.is_empty() || peers.values().any(|p| p.version < FOLDING_VERSION) {
+        return HashSet::new();
+    }
+    let mut foldable = selections.departed.lock().unwrap().clone();
+    for p in peers.values() {
+        let reported = p.departed.lock().unwrap();
+        foldable.retain(|id| reported.contains(id));
+    }
+    foldable
+}
+
+/// Sets [selection] to [clipboard] with this node as the source, incrementing its state, and
+/// sends CopyNotifications to all [peers].
+/// The clock entries of departed peers are folded when incrementing, as far as the [peers]
+/// agree that they are gone.
+/// Clipboards of at most [push_size] bytes are pushed along with the notifications.
+/// Returns the new state.
+fn publish_clipboard(
+    own: &Node,
+    selections: &Selections,
+    peers: &Mutex<HashMap<PeerID, Peer>>,
+    seen_copy_notifications: &Mutex<SeenCache<MessageID>>,
+    push_size: usize,
+    selection: Selection,
+    clipboard: &ClipboardContent,
+) -> CopyClock {
+    let foldable = foldable(selections, &peers.lock().unwrap());
+    let selection_state = selections.get(selection);
+    let mut current = selection_state.clipboard.lock().unwrap();
+    *current = clipboard.clone();
+
+    let mut overlay_state = selection_state.state.lock().unwrap();
+    // Departed peers don't copy anymore, so their entries are folded. Our new state replaces
+    // the old one everywhere, this way the clock doesn't grow forever. As our peers agree on
+    // who is gone, the nodes around us fold the same entries.
+    let mut clock = overlay_state.clock.clone();
+    clock.fold(|id| !foldable.contains(id));
+    // Our copy happened after the one we replace, even if the clock of its peer is ahead.
+    let time = selections.hlc.lock().unwrap().observe(overlay_state.time);
+    *overlay_state = CopyClock {
+        clock: clock.incr_clone(own.id),
+        last_copy_src: own.id,
+        last_copy_endpoint: own.endpoint,
+        time: time,
+    };
+    let state = overlay_state.clone();
+    println!("set_clipboard: set state to {:?}", state);
+    drop(overlay_state);
+    drop(current);
+    let digests = TargetDigest::of_content(clipboard);
+    let pushed = if !clipboard.is_empty() && clipboard.len() <= push_size {
+        Some(clipboard.clone())
+    } else {
+        None
+    };
+
+    // Our own notification must not be forwarded when it comes back to us.
+    let message_id = generate_message_id();
+    seen_copy_notifications.lock().unwrap().insert(message_id);
+    {
+        let peers = peers.lock().unwrap();
+        for (ep, p) in peers.iter() {
+            println!("set_clipboard: sending notification to peer {:?}", ep);
+
+            let resp = p.notify_copy(
+                selection,
+                state.clone(),
+                digests.clone(),
+                pushed.clone(),
+                message_id,
+            );
+            if let Err(e) = resp {
+                println!("set_clipboard: unable to send: {}", e);
+                continue;
+            }
+            println!("set_clipboard: send successful");
+        }
+    }
+
+    state
+}
+
+/// A ShutdownSignal tells the background threads of an Overlay to stop.
+struct ShutdownSignal {
+    signalled: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl ShutdownSignal {
+    fn new() -> ShutdownSignal {
+        ShutdownSignal {
+            signalled: Mutex::new(false),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Signals all threads to stop and wakes up the sleeping ones.
+    fn signal(&self) {
+        *self.signalled.lock().unwrap() = true;
+        self.cond.notify_all();
+    }
+
+    /// Returns whether the threads should stop.
+    fn is_signalled(&self) -> bool {
+        *self.signalled.lock().unwrap()
+    }
+
+    /// Sleeps for [duration] or until the signal is given, returning whether it was.
+    fn sleep(&self, duration: time::Duration) -> bool {
+        let deadline = time::Instant::now() + duration;
+        let mut signalled = self.signalled.lock().unwrap();
+        while !*signalled {
+            let now = time::Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            signalled = self.cond.wait_timeout(signalled, deadline - now).unwrap().0;
         }
+        true
     }
 }
 
+/// Binds a socket on the given port that accepts connections over both IPv4 and IPv6.
+fn bind_dual_stack(port: u16) -> io::Result<TcpListener> {
+    let builder = TcpBuilder::new_v6()?;
+    builder.only_v6(false)?;
+    builder.bind((Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), port))?;
+    builder.listen(128)
+}
+
+/// Binds a socket on the given address and port, or on all addresses over both IPv4 and IPv6 if
+/// [addr] is unspecified.
+fn bind_listener(addr: &IpAddr, port: u16) -> io::Result<TcpListener> {
+    if !addr.is_unspecified() {
+        return TcpListener::bind((addr.clone(), port));
+    }
+    match bind_dual_stack(port) {
+        Ok(sock) => Ok(sock),
+        Err(e) => {
+            println!("overlay: unable to listen on IPv4 and IPv6, only using {}: {}", addr, e);
+            TcpListener::bind((addr.clone(), port))
+        }
+    }
+}
+
+/// Returns whether a new connection to [remote_id], [incoming] or not, replaces the [existing]
+/// one instead of being dropped.
+/// A peer that connects again replaces its old connection. If both of us connected at the same
+/// time, both ends keep the connection opened by the peer with the smaller ID.
+fn replaces(existing: &Peer, incoming: bool, own_id: PeerID, remote_id: PeerID) -> bool {
+    if existing.incoming == incoming {
+        return true;
+    }
+    let opener = if incoming { remote_id } else { own_id };
+    opener == cmp::min(own_id, remote_id)
+}
+
 /// A Peer encapsulates behaviour about a peer connected over a P2PConnection.
 #[derive(Debug)]
 struct Peer {
+    endpoint: Endpoint,
+    version: u32,
+    /// Whether the remote opened the connection.
+    incoming: bool,
     sender: SyncSender<P2PSend>,
     closer: SyncSender<()>,
+    health: Arc<Mutex<PeerHealth>>,
+    /// The peers the remote reported as departed in its last Ping or Pong.
+    departed: Arc<Mutex<Vec<PeerID>>>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+/// PeerHealth tracks whether a peer answers our Pings.
+#[derive(Debug)]
+struct PeerHealth {
+    last_pong: Option<time::Instant>,
+    unanswered_pings: u32,
 }
 
 impl Peer {
     fn new(
         mut conn: P2PConnection,
-        own_id: PeerID,
+        own: Node,
         remote_id: PeerID,
+        remote_endpoint: Endpoint,
         peers: Arc<Mutex<HashMap<PeerID, Peer>>>,
-        overlay_state: Arc<Mutex<CopyClock>>,
+        available_peers: Arc<Mutex<Vec<Node>>>,
+        selections: Arc<Selections>,
+        seen_copy_notifications: Arc<Mutex<SeenCache<MessageID>>>,
+        relays: Arc<Relays>,
+        config: Arc<ConnectionConfig>,
     ) -> Result<Peer, Box<Error>> {
+        let version = conn.version();
+        let incoming = conn.is_incoming();
         let mut conn2 = conn.dup()?;
-        let (send_tx, send_rx) = sync_channel(0);
+        let (send_tx, send_rx) = sync_channel(PEER_SEND_QUEUE);
         let (close_tx, close_rx) = sync_channel(0);
         let close_copy = close_tx.clone();
         let send_copy = send_tx.clone();
-        let id_copy = own_id.clone();
+        let own_copy = own.clone();
+        let health = Arc::new(Mutex::new(PeerHealth {
+            last_pong: None,
+            unanswered_pings: 0,
+        }));
+        let health_copy = health.clone();
+        let departed = Arc::new(Mutex::new(Vec::new()));
+        let departed_copy = departed.clone();
+
+        // A healthy peer pings us and answers our pings, so it sends something at least every
+        // ping interval. If it doesn't for longer than we tolerate, the read fails.
+        conn.set_timeouts(
+            time::Duration::from_secs(PING_INTERVAL_SECS * (MAX_MISSED_PONGS as u64 + 1)),
+            time::Duration::from_secs(PEER_WRITE_TIMEOUT_SECS),
+        )?;
 
         // Start a read loop.
-        thread::spawn(move || loop {
+        let reader = thread::spawn(move || loop {
             let msg = conn.read_message();
             if let Err(e) = msg {
                 println!("peer: unable to read, closing: {}", e);
@@ -78,13 +838,26 @@ impl Peer {
             let msg = msg.unwrap();
             // TODO maybe move this to another thread?
             match msg.message_type {
-                MessageType::Ping { state } => {
-                    println!("peer: received ping with state: {:?}", state);
-                    let new_state = update_state(overlay_state.clone(), state);
-                    println!("peer: updated overlay state to {:?}", new_state);
+                MessageType::Ping {
+                    state,
+                    primary,
+                    departed,
+                } => {
+                    println!("peer: received ping with states: {:?}, {:?}", state, primary);
+                    *departed_copy.lock().unwrap() = departed;
+                    let (new_state, new_primary) = selections.update(state, primary);
+                    println!(
+                        "peer: updated overlay states to {:?}, {:?}",
+                        new_state, new_primary
+                    );
 
                     println!("peer: replying with pong");
-                    let resp = conn.pong(&new_state, &id_copy);
+                    let resp = conn.pong(
+                        &new_state,
+                        &new_primary,
+                        &selections.departed_peers(),
+                        &own_copy,
+                    );
                     if let Err(e) = resp {
                         println!("peer: unable to reply, closing: {}", e);
                         conn.close();
@@ -94,26 +867,70 @@ impl Peer {
                     }
                     println!("peer: reply successful");
                 }
-                MessageType::Pong { state } => {
-                    println!("peer: received pong with state: {:?}", state);
-                    let new_state = update_state(overlay_state.clone(), state);
-                    println!("peer: updated overlay state to {:?}", new_state);
+                MessageType::Pong {
+                    state,
+                    primary,
+                    departed,
+                } => {
+                    println!("peer: received pong with states: {:?}, {:?}", state, primary);
+                    *departed_copy.lock().unwrap() = departed;
+                    {
+                        let mut health = health_copy.lock().unwrap();
+                        health.last_pong = Some(time::Instant::now());
+                        health.unanswered_pings = 0;
+                    }
+                    let (new_state, new_primary) = selections.update(state, primary);
+                    println!(
+                        "peer: updated overlay states to {:?}, {:?}",
+                        new_state, new_primary
+                    );
                 }
-                MessageType::CopyNotification { state } => {
+                MessageType::CopyNotification {
+                    state,
+                    selection,
+                    digests,
+                    content,
+                } => {
                     println!(
-                        "peer: received copy notification with state: {:?}, ttl: {}",
-                        state, msg.ttl
+                        "peer: received copy notification for {:?} with state: {:?}, ttl: {}",
+                        selection, state, msg.ttl
                     );
-                    let new_state = update_state(overlay_state.clone(), state);
+                    let selection_state = selections.get(selection);
+                    let new_state = update_state(&selection_state.state, state.clone(), selections.policy);
                     println!("peer: updated overlay state to {:?}", new_state);
+                    if new_state == state {
+                        // Whoever copied this is still around.
+                        selections.departed.lock().unwrap().remove(&state.last_copy_src);
+                    }
+                    // Digests and content describe the notified state only, not a newer one.
+                    let (digests, content) = if new_state == state {
+                        (digests, content)
+                    } else {
+                        (Vec::new(), None)
+                    };
+                    // Cached first, so that whoever sees the digests finds the content too.
+                    if !digests.is_empty() {
+                        if let Some(ref content) = content {
+                            selection_state.cache_pushed(&state, &digests, content);
+                        }
+                        *selection_state.digests.lock().unwrap() =
+                            Some((state.clone(), digests.clone()));
+                    }
+                    if !seen_copy_notifications
+                        .lock()
+                        .unwrap()
+                        .insert(msg.message_id)
+                    {
+                        println!("peer: copy notification seen before, not forwarding");
+                        continue;
+                    }
                     if msg.ttl <= 1 {
                         println!("peer: copy notification ttl is {}, not forwarding", msg.ttl);
                         continue;
                     }
-                    // TODO compare message_id against seen message IDs, don't forward
+                    let message_id = msg.message_id;
                     let peers = peers.clone();
                     let remote_id = remote_id.clone();
-                    let own_id = own_id.clone();
                     let new_ttl = msg.ttl - 1;
                     let new_hop_count = msg.hop_count + 1;
                     thread::spawn(move || {
@@ -125,8 +942,15 @@ impl Peer {
                             }
                             println!("peer: forwarding copy notification to peer {:?}", ep);
 
-                            let resp =
-                                p.forward_notify_copy(new_state.clone(), new_ttl, new_hop_count);
+                            let resp = p.forward_notify_copy(
+                                selection,
+                                new_state.clone(),
+                                digests.clone(),
+                                content.clone(),
+                                message_id,
+                                new_ttl,
+                                new_hop_count,
+                            );
                             if let Err(e) = resp {
                                 println!("peer: unable to forward: {}", e);
                                 continue;
@@ -135,6 +959,272 @@ impl Peer {
                         }
                     });
                 }
+                MessageType::ReplicaNotification { replica } => {
+                    println!(
+                        "peer: received replica notification for {:?} held by {:?}, ttl: {}",
+                        replica.selection, replica.holder.id, msg.ttl
+                    );
+                    let selection_state = selections.get(replica.selection);
+                    let new_state = update_state(
+                        &selection_state.state,
+                        replica.state.clone(),
+                        selections.policy,
+                    );
+                    if new_state == replica.state && replica.holder.id != own_copy.id {
+                        selection_state.add_replica(replica.clone());
+                    }
+                    if !seen_copy_notifications
+                        .lock()
+                        .unwrap()
+                        .insert(msg.message_id)
+                    {
+                        println!("peer: replica notification seen before, not forwarding");
+                        continue;
+                    }
+                    if msg.ttl <= 1 {
+                        println!(
+                            "peer: replica notification ttl is {}, not forwarding",
+                            msg.ttl
+                        );
+                        continue;
+                    }
+                    let message_id = msg.message_id;
+                    let peers = peers.clone();
+                    let remote_id = remote_id.clone();
+                    let new_ttl = msg.ttl - 1;
+                    let new_hop_count = msg.hop_count + 1;
+                    thread::spawn(move || {
+                        let peers = peers.lock().unwrap();
+                        for (ep, p) in peers.iter() {
+                            if ep.eq(&remote_id) {
+                                continue;
+                            }
+                            println!("peer: forwarding replica notification to peer {:?}", ep);
+                            let resp = p.notify_replica(
+                                replica.clone(),
+                                message_id,
+                                new_ttl,
+                                new_hop_count,
+                            );
+                            if let Err(e) = resp {
+                                println!("peer: unable to forward: {}", e);
+                            }
+                        }
+                    });
+                }
+                MessageType::RelayRequest {
+                    source,
+                    selection,
+                    targets,
+                    compression,
+                    max_chunk,
+                } => {
+                    println!(
+                        "peer: received relay request for {:?} held by {:?}, ttl: {}",
+                        selection, source, msg.ttl
+                    );
+                    if !seen_copy_notifications
+                        .lock()
+                        .unwrap()
+                        .insert(msg.message_id)
+                    {
+                        println!("peer: relay request seen before, dropping");
+                        continue;
+                    }
+                    relays.add_route(msg.message_id, remote_id, selection);
+                    let message_id = msg.message_id;
+                    let peers = peers.clone();
+                    let remote_id = remote_id.clone();
+                    if source == own_copy.id {
+                        let own = own_copy.clone();
+                        let selections = selections.clone();
+                        // Answered over this connection even if we left and the peer is not
+                        // among our peers anymore, it may be fetching our handover.
+                        let sender = send_copy.clone();
+                        thread::spawn(move || {
+                            let responses = relay_responses(
+                                &own,
+                                selections.get(selection),
+                                &targets,
+                                max_chunk,
+                                &compression,
+                            );
+                            for response in responses {
+                                let resp = sender.send(P2PSend::RelayResponse(message_id, response));
+                                if let Err(e) = resp {
+                                    println!("peer: unable to answer relay request: {}", e);
+                                    return;
+                                }
+                            }
+                            println!("peer: answered relay request");
+                        });
+                        continue;
+                    }
+                    if msg.ttl <= 1 {
+                        println!("peer: relay request ttl is {}, not forwarding", msg.ttl);
+                        continue;
+                    }
+                    let new_ttl = msg.ttl - 1;
+                    let new_hop_count = msg.hop_count + 1;
+                    thread::spawn(move || {
+                        let peers = peers.lock().unwrap();
+                        // Straight to the source if it is one of our peers.
+                        let next: Vec<(&PeerID, &Peer)> = match peers.get(&source) {
+                            Some(p) => vec![(&source, p)],
+                            None => peers.iter().filter(|&(id, _)| *id != remote_id).collect(),
+                        };
+                        for (id, p) in next {
+                            println!("peer: forwarding relay request to peer {:?}", id);
+                            let resp = p.relay_request(
+                                source,
+                                selection,
+                                targets.clone(),
+                                compression.clone(),
+                                max_chunk,
+                                message_id,
+                                new_ttl,
+                                new_hop_count,
+                            );
+                            if let Err(e) = resp {
+                                println!("peer: unable to forward: {}", e);
+                            }
+                        }
+                    });
+                }
+                MessageType::RelayResponse { request, response } => match relays.route(&request) {
+                    Some((from, selection)) => {
+                        // Forwarded right here, so that the parts of the answer stay in order.
+                        // A slow peer may stall this connection for a bit, but parts of an
+                        // answer are never dropped: if it doesn't catch up, the relay is given up
+                        // and the requester is told so.
+                        let sender = peers.lock().unwrap().get(&from).map(|p| p.sender.clone());
+                        let resp = match sender {
+                            Some(ref sender) => send_within(
+                                sender,
+                                P2PSend::RelayResponse(request, response),
+                                time::Duration::from_secs(RELAY_FORWARD_SECS),
+                            ),
+                            None => Err(format!("{:?} is gone", from)),
+                        };
+                        if let Err(e) = resp {
+                            println!("peer: unable to relay response, giving up: {}", e);
+                            relays.abandon(&request);
+                            if let Some(sender) = sender {
+                                let error = RelayAnswer::ErrorResponse {
+                                    state: selections.get(selection).state.lock().unwrap().clone(),
+                                    error: format!("a relaying peer gave up: {}", e),
+                                };
+                                // Queued once there is room again, without holding up this
+                                // connection any longer.
+                                thread::spawn(move || {
+                                    let _ = sender.send(P2PSend::RelayResponse(request, error));
+                                });
+                            }
+                        }
+                    }
+                    None => if !relays.deliver(&request, response) {
+                        println!("peer: received relay response for an unknown request, dropping");
+                    },
+                },
+                MessageType::Leave {
+                    replacements,
+                    handover,
+                } => {
+                    println!(
+                        "peer: {:?} is leaving, suggesting {:?}",
+                        remote_id, replacements
+                    );
+                    selections.departed.lock().unwrap().insert(remote_id);
+                    {
+                        // Our other peers learn right away, so that they can agree on folding.
+                        let mut peers = peers.lock().unwrap();
+                        peers.remove(&remote_id);
+                        let (state, primary) = selections.states();
+                        for p in peers.values() {
+                            if let Err(e) =
+                                p.ping(state.clone(), primary.clone(), selections.departed_peers())
+                            {
+                                println!("peer: unable to report departure: {}", e);
+                            }
+                        }
+                    }
+                    {
+                        let mut available = available_peers.lock().unwrap();
+                        available.retain(|a| a.id != remote_id);
+                        for node in replacements {
+                            if node.id != own_copy.id && !available.iter().any(|a| a.id == node.id)
+                            {
+                                available.push(node);
+                            }
+                        }
+                    }
+                    if handover.is_empty() {
+                        conn.close();
+                        close_copy.send(());
+                        println!("peer: closed");
+                        return;
+                    }
+
+                    // The answers to our RelayRequests arrive here, so the handover is fetched
+                    // in another thread, which closes the connection when done.
+                    let own = own_copy.clone();
+                    let selections = selections.clone();
+                    let peers = peers.clone();
+                    let seen_copy_notifications = seen_copy_notifications.clone();
+                    let relays = relays.clone();
+                    let config = config.clone();
+                    let sender = send_copy.clone();
+                    let closer = close_copy.clone();
+                    thread::spawn(move || {
+                        for h in handover {
+                            // Someone may have copied something newer in the meantime.
+                            let state = update_state(
+                                &selections.get(h.selection).state,
+                                h.state.clone(),
+                                selections.policy,
+                            );
+                            if state.last_copy_src != remote_id {
+                                println!(
+                                    "peer: not taking over {:?}, {:?} is newer",
+                                    h.selection, state
+                                );
+                                continue;
+                            }
+                            let content = match h.content {
+                                Some(ref content) => Ok(content.clone()),
+                                None => fetch_handover(
+                                    remote_id,
+                                    &h,
+                                    &sender,
+                                    &selections,
+                                    &seen_copy_notifications,
+                                    &relays,
+                                    &config,
+                                ),
+                            };
+                            let content = match content {
+                                Ok(content) => content,
+                                Err(e) => {
+                                    println!("peer: unable to take over {:?}: {}", h.selection, e);
+                                    continue;
+                                }
+                            };
+                            println!("peer: taking over {:?} from {:?}", h.selection, remote_id);
+                            let state = publish_clipboard(
+                                &own,
+                                &selections,
+                                &peers,
+                                &seen_copy_notifications,
+                                config.max_push_size(),
+                                h.selection,
+                                &content,
+                            );
+                            println!("peer: took over {:?} with state {:?}", h.selection, state);
+                            *selections.get(h.selection).taken_over.lock().unwrap() = Some(content);
+                        }
+                        closer.send(());
+                    });
+                }
                 _ => {
                     println!("peer: received invalid message: {:?}", msg);
                     conn.close();
@@ -146,35 +1236,80 @@ impl Peer {
         });
 
         // Start a write loop.
-        thread::spawn(move || loop {
+        let writer = thread::spawn(move || loop {
             select! {
                 msg = send_rx.recv() => {
                     let msg = msg.unwrap();
                     println!("peer: received data to send: {:?}",msg);
                     match msg {
-                        P2PSend::Ping(clock) => {
-                            let resp = conn2.ping(&clock,&own_id);
+                        P2PSend::Ping(clock,primary,departed) => {
+                            let resp = conn2.ping(&clock,&primary,&departed,&own);
                             if let Err(e) = resp {
                                 println!("peer: unable to send, closing: {}",e);
                                 conn2.close();
                                 return
                             }
                         },
-                        P2PSend::CopyNotification(clock) => {
-                            let resp = conn2.notify_copy(&clock,&own_id);
+                        P2PSend::CopyNotification(selection,clock,digests,content,message_id) => {
+                            let resp = conn2.notify_copy(selection,&clock,&digests,content.as_ref(),message_id,&own);
                             if let Err(e) = resp {
                                 println!("peer: unable to send, closing: {}",e);
                                 conn2.close();
                                 return
                             }
                         },
-                        P2PSend::ForwardCopyNotification(clock,ttl,hop_count) => {
-                            let resp = conn2.forward_notify_copy(&clock,&own_id,ttl,hop_count);
+                        P2PSend::ForwardCopyNotification(selection,clock,digests,content,message_id,ttl,hop_count) => {
+                            let resp = conn2.forward_notify_copy(selection,&clock,&digests,content.as_ref(),&own,message_id,ttl,hop_count);
+                            if let Err(e) = resp {
+                                println!("peer: unable to send, closing: {}",e);
+                                conn2.close();
+                                return
+                            }
+                        },
+                        P2PSend::ReplicaNotification(replica,message_id,ttl,hop_count) => {
+                            // Older peers would drop the connection on a message they don't know.
+                            if conn2.version() < REPLICATION_VERSION {
+                                println!("peer: speaks version {}, not sending replica notification",conn2.version());
+                            } else if let Err(e) = conn2.notify_replica(&replica,&own,message_id,ttl,hop_count) {
+                                println!("peer: unable to send, closing: {}",e);
+                                conn2.close();
+                                return
+                            }
+                        },
+                        P2PSend::RelayRequest(source,selection,targets,compression,max_chunk,message_id,ttl,hop_count) => {
+                            if conn2.version() < RELAY_VERSION {
+                                println!("peer: speaks version {}, not sending relay request",conn2.version());
+                            } else if let Err(e) = conn2.relay_request(source,selection,&targets,&compression,max_chunk,&own,message_id,ttl,hop_count) {
+                                println!("peer: unable to send, closing: {}",e);
+                                conn2.close();
+                                return
+                            }
+                        },
+                        P2PSend::RelayResponse(request,response) => {
+                            let resp = conn2.relay_response(request,&response,&own);
                             if let Err(e) = resp {
                                 println!("peer: unable to send, closing: {}",e);
                                 conn2.close();
                                 return
                             }
+                        },
+                        P2PSend::Leave(replacements,handover,result) => {
+                            let resp = conn2.leave(&replacements,&handover,&own);
+                            let sent = resp.is_ok();
+                            result.send(resp.map_err(|e| e.to_string()));
+                            if !sent || handover.is_empty() {
+                                println!("peer: left, closing");
+                                conn2.close();
+                                return
+                            }
+                            // The peer fetches the handover and closes the connection.
+                            println!("peer: left, waiting for the handover to be fetched");
+                            let timeout = time::Duration::from_secs(HANDOVER_TIMEOUT_SECS);
+                            if let Err(e) = conn2.set_timeouts(timeout, timeout) {
+                                println!("peer: unable to wait for handover, closing: {}",e);
+                                conn2.close();
+                                return
+                            }
                         }
                     }
                     println!("peer: send successful");
@@ -188,22 +1323,57 @@ impl Peer {
         });
 
         Ok(Peer {
+            endpoint: remote_endpoint,
+            version: version,
+            incoming: incoming,
             sender: send_tx,
             closer: close_tx,
+            health: health,
+            departed: departed,
+            threads: vec![reader, writer],
         })
     }
 
     /// Enqueues a Ping to be sent to the peer.
-    /// This usually returns an error if the connection died for some reason.
-    fn ping(&self, state: CopyClock) -> Result<(), Box<Error>> {
-        self.sender.send(P2PSend::Ping(state))?;
+    /// This returns an error if the connection died for some reason or the peer did not answer
+    /// the last `MAX_MISSED_PONGS` Pings.
+    fn ping(
+        &self,
+        state: CopyClock,
+        primary: CopyClock,
+        departed: Vec<PeerID>,
+    ) -> Result<(), Box<Error>> {
+        {
+            let mut health = self.health.lock().unwrap();
+            if health.unanswered_pings >= MAX_MISSED_PONGS {
+                return Err(From::from(format!(
+                    "{} pings unanswered, last pong at {:?}",
+                    health.unanswered_pings, health.last_pong
+                )));
+            }
+            health.unanswered_pings += 1;
+        }
+        self.sender.send(P2PSend::Ping(state, primary, departed))?;
         Ok(())
     }
 
     /// Enqueues a CopyNotification to be sent to the peer.
     /// This usually returns an error if the connection died for some reason.
-    fn notify_copy(&self, state: CopyClock) -> Result<(), Box<Error>> {
-        self.sender.send(P2PSend::CopyNotification(state))?;
+    fn notify_copy(
+        &self,
+        selection: Selection,
+        state: CopyClock,
+        digests: Vec<TargetDigest>,
+        content: Option<ClipboardContent>,
+        message_id: MessageID,
+    ) -> Result<(), Box<Error>> {
+        self.sender.send(P2PSend::CopyNotification(
+            selection,
+            state,
+            digests,
+            content,
+            message_id,
+        ))?;
         Ok(())
     }
 
@@ -211,12 +1381,76 @@ impl Peer {
     /// This usually returns an error if the connection died for some reason.
     fn forward_notify_copy(
         &self,
+        selection: Selection,
         state: CopyClock,
+        digests: Vec<TargetDigest>,
+        content: Option<ClipboardContent>,
+        message_id: MessageID,
         ttl: u32,
         hop_count: u32,
     ) -> Result<(), Box<Error>> {
-        self.sender
-            .send(P2PSend::ForwardCopyNotification(state, ttl, hop_count))?;
+        self.sender.send(P2PSend::ForwardCopyNotification(
+            selection,
+            state,
+            digests,
+            content,
+            message_id,
+            ttl,
+            hop_count,
+        ))?;
+        Ok(())
+    }
+
+    /// Enqueues a ReplicaNotification to be sent to the peer, if it understands it.
+    /// This usually returns an error if the connection died for some reason.
+    fn notify_replica(
+        &self,
+        replica: Replica,
+        message_id: MessageID,
+        ttl: u32,
+        hop_count: u32,
+    ) -> Result<(), Box<Error>> {
+        self.sender.send(P2PSend::ReplicaNotification(
+            replica,
+            message_id,
+            ttl,
+            hop_count,
+        ))?;
+        Ok(())
+    }
+
+    /// Enqueues a RelayRequest, our own or one we forward, to be sent to the peer.
+    fn relay_request(
+        &self,
+        source: PeerID,
+        selection: Selection,
+        targets: Vec<String>,
+        compression: Vec<Compression>,
+        max_chunk: u32,
+        message_id: MessageID,
+        ttl: u32,
+        hop_count: u32,
+    ) -> Result<(), Box<Error>> {
+        self.sender.send(P2PSend::RelayRequest(
+            source,
+            selection,
+            targets,
+            compression,
+            max_chunk,
+            message_id,
+            ttl,
+            hop_count,
+        ))?;
+
+        Ok(())
+    }
+
+    /// Sends a Leave to the peer and closes the connection afterwards.
+    /// This usually returns an error if the connection died for some reason.
+    fn leave(&self, replacements: Vec<Node>, handover: Vec<Handover>) -> Result<(), Box<Error>> {
+        let (result_tx, result_rx) = channel();
+        self.sender.send(P2PSend::Leave(replacements, handover, result_tx))?;
+        result_rx.recv()??;
         Ok(())
     }
 
@@ -225,139 +1459,645 @@ impl Peer {
         self.closer.send(())?;
         Ok(())
     }
+
+    /// Waits for the read and write loops to finish, i.e. for the connection to be closed.
+    fn join(self) {
+        for t in self.threads {
+            if t.join().is_err() {
+                println!("peer: thread panicked");
+            }
+        }
+    }
 }
 
 /// An Overlay encapsulates the functionality of one node in the overlay network.
 pub struct Overlay {
-    own_id: PeerID,
-    sock: Arc<Mutex<TcpListener>>,
-    bootstrap_ids: Vec<PeerID>,
-    available_ids: Mutex<Vec<PeerID>>,
+    own: Node,
+    sock: Arc<Mutex<Option<TcpListener>>>,
+    bootstrap_endpoints: Vec<Endpoint>,
+    available_peers: Arc<Mutex<Vec<Node>>>,
+    min_degree: usize,
+    max_degree: usize,
     connected_peers: Arc<Mutex<HashMap<PeerID, Peer>>>,
-    // TODO maybe have state, clipboard in one mutex for more safety
-    state: Arc<Mutex<CopyClock>>,
-    clipboard: Arc<Mutex<String>>,
-    seen_join_message_ids: Arc<Mutex<HashMap<MessageID, ()>>>,
-    cached_clipboard: Arc<Mutex<String>>,
-    cache_state: Arc<Mutex<CopyClock>>,
+    selections: Arc<Selections>,
+    seen_join_message_ids: Arc<Mutex<SeenCache<MessageID>>>,
+    seen_copy_notifications: Arc<Mutex<SeenCache<MessageID>>>,
+    relays: Arc<Relays>,
+    config: Arc<ConnectionConfig>,
+    stats: Arc<Mutex<TransferStats>>,
+    shutdown: Arc<ShutdownSignal>,
+    threads: Mutex<Vec<thread::JoinHandle<()>>>,
+    /// The threads handling accepted connections.
+    handlers: Arc<Mutex<Vec<thread::JoinHandle<()>>>>,
 }
 
 impl Overlay {
-    /// Creates a new overlay, listening on the given IP address, saving the given [bootstrap_peers]
-    /// for bootstrapping.
+    /// Creates a new overlay, listening on the given IP address and port, saving the given
+    /// [bootstrap_peers] for bootstrapping.
+    /// All connections to and from other peers are established as given by [config], i.e. they
+    /// are authenticated and encrypted using its keys.
+    /// If [addr] is unspecified, the node listens on all local addresses, IPv4 and IPv6 if
+    /// available. A [port] of 0 picks a random one.
     /// Note that the node has not yet joined the network and is not accepting connections after
     /// this function returns.
-    /// Call `start_accepting`, `perform_join`, and `start_autoping` on the returned overlay.
-    pub fn new(addr: &Ipv4Addr, bootstrap_peers: Vec<PeerID>) -> Result<Overlay, Box<Error>> {
-        let sock = TcpListener::bind((addr.clone(), 0 as u16))?;
+    /// Call `start_accepting`, `perform_join`, `start_autoping` and `start_maintenance` on the
+    /// returned overlay, and `shutdown` to stop it again.
+    pub fn new(
+        addr: &IpAddr,
+        port: u16,
+        bootstrap_peers: Vec<Endpoint>,
+        config: ConnectionConfig,
+    ) -> Result<Overlay, Box<Error>> {
+        let sock = bind_listener(addr, port)?;
         let local = sock.local_addr()?;
         println!("overlay: bound to address {}", local);
 
+        let own = Node {
+            id: config.keys.id(),
+            endpoint: Endpoint::new(&addr, local.port()),
+        };
+        println!("overlay: own ID is {:?}", own.id);
+
         Ok(Overlay {
-            own_id: PeerID::new(&addr, local.port()),
-            sock: Arc::new(Mutex::new(sock)),
-            bootstrap_ids: bootstrap_peers,
-            available_ids: Mutex::new(Vec::new()),
+            own: own,
+            sock: Arc::new(Mutex::new(Some(sock))),
+            bootstrap_endpoints: bootstrap_peers,
+            available_peers: Arc::new(Mutex::new(Vec::new())),
+            min_degree: DEFAULT_MIN_DEGREE,
+            max_degree: DEFAULT_MAX_DEGREE,
             connected_peers: Arc::new(Mutex::new(HashMap::new())),
-            state: Arc::new(Mutex::new(CopyClock::new(
-                &VectorClock::new(),
-                &PeerID::new(&addr, local.port()),
+            selections: Arc::new(Selections::new(&own, config.conflict_policy)),
+            seen_join_message_ids: Arc::new(Mutex::new(SeenCache::new(
+                SEEN_JOIN_REQUESTS,
+                time::Duration::from_secs(SEEN_JOIN_REQUESTS_SECS),
             ))),
-            clipboard: Arc::new(Mutex::new(String::new())),
-            seen_join_message_ids: Arc::new(Mutex::new(HashMap::new())),
-            cached_clipboard: Arc::new(Mutex::new(String::new())),
-            cache_state: Arc::new(Mutex::new(CopyClock::new(
-                &VectorClock::new(),
-                &PeerID::new(&addr, local.port()),
+            seen_copy_notifications: Arc::new(Mutex::new(SeenCache::new(
+                SEEN_COPY_NOTIFICATIONS,
+                time::Duration::from_secs(SEEN_COPY_NOTIFICATIONS_SECS),
             ))),
+            relays: Arc::new(Relays::new()),
+            config: Arc::new(config),
+            stats: Arc::new(Mutex::new(TransferStats::default())),
+            shutdown: Arc::new(ShutdownSignal::new()),
+            threads: Mutex::new(Vec::new()),
+            handlers: Arc::new(Mutex::new(Vec::new())),
         })
     }
 
-    /// Sets the clipboard to the given [clipboard].
-    /// This increments the state accordingly and sends out CopyNotifications.
-    pub fn set_clipboard(&self, clipboard: &str) -> Result<(), Box<Error>> {
-        let mut current = self.clipboard.lock().unwrap();
-        *current = clipboard.to_string();
+    /// Returns the ID of this node.
+    pub fn id(&self) -> PeerID {
+        self.own.id
+    }
+
+    /// Returns the endpoint this node is listening on.
+    pub fn endpoint(&self) -> Endpoint {
+        self.own.endpoint
+    }
 
-        let mut overlay_state = self.state.lock().unwrap();
-        *overlay_state = CopyClock {
-            clock: overlay_state.clock.incr_clone(self.own_id.clone()),
-            last_copy_src: self.own_id.clone(),
-        };
-        let state = overlay_state.clone();
-        println!("set_clipboard: set state to {:?}", state);
-        drop(overlay_state);
-        drop(current);
+    /// Returns the counters of the caches of seen JoinRequests and CopyNotifications, in that
+    /// order.
+    fn seen_stats(&self) -> (SeenStats, SeenStats) {
+        (
+            self.seen_join_message_ids.lock().unwrap().stats(),
+            self.seen_copy_notifications.lock().unwrap().stats(),
+        )
+    }
 
-        {
-            let peers = self.connected_peers.lock().unwrap();
-            for (ep, p) in peers.iter() {
-                println!("set_clipboard: sending notification to peer {:?}", ep);
+    /// Returns the current state of [selection].
+    #[cfg(test)]
+    pub fn state(&self, selection: Selection) -> CopyClock {
+        self.selections.get(selection).state.lock().unwrap().clone()
+    }
 
-                let resp = p.notify_copy(state.clone());
-                if let Err(e) = resp {
-                    println!("set_clipboard: unable to send: {}", e);
-                    continue;
+    /// Returns the state the digests of [selection] were announced for, if any.
+    #[cfg(test)]
+    pub fn announced_state(&self, selection: Selection) -> Option<CopyClock> {
+        let digests = self.selections.get(selection).digests.lock().unwrap();
+        digests.as_ref().map(|&(ref state, _)| state.clone())
+    }
+
+    /// Returns the number of announced replicas of the current content of [selection].
+    #[cfg(test)]
+    pub fn replica_count(&self, selection: Selection) -> usize {
+        self.selections.get(selection).replicas.lock().unwrap().len()
+    }
+
+    /// Returns the departed peers whose clock entries would be folded by our next copy.
+    #[cfg(test)]
+    pub fn foldable_peers(&self) -> HashSet<PeerID> {
+        foldable(&self.selections, &self.connected_peers.lock().unwrap())
+    }
+
+    /// Returns the number of peers this node is connected to.
+    #[cfg(test)]
+    pub fn peer_count(&self) -> usize {
+        self.connected_peers.lock().unwrap().len()
+    }
+
+    /// Returns the statistics of the clipboard transfers so far.
+    pub fn stats(&self) -> TransferStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Sets [selection] to the given [clipboard].
+    /// This increments the state of that selection accordingly and sends out CopyNotifications.
+    pub fn set_clipboard(
+        &self,
+        selection: Selection,
+        clipboard: &ClipboardContent,
+    ) -> Result<(), Box<Error>> {
+        *self.selections.get(selection).taken_over.lock().unwrap() = None;
+        publish_clipboard(
+            &self.own,
+            &self.selections,
+            &self.connected_peers,
+            &self.seen_copy_notifications,
+            self.config.max_push_size(),
+            selection,
+            clipboard,
+        );
+
+        Ok(())
+    }
+
+    /// Leaves the overlay: every connected peer is sent a Leave suggesting some of our other
+    /// peers to connect to instead, and all connections are closed.
+    /// If this node has the latest content of a selection, it is handed over to one of the
+    /// peers, so that it can still be pasted after we are gone.
+    pub fn leave(&self) {
+        let mut handover = Vec::new();
+        for selection in &[Selection::Clipboard, Selection::Primary] {
+            let selection_state = self.selections.get(*selection);
+            let state = selection_state.state.lock().unwrap().clone();
+            let content = selection_state.clipboard.lock().unwrap().clone();
+            if state.last_copy_src == self.own.id && !content.is_empty() {
+                handover.push(Handover {
+                    selection: *selection,
+                    state: state,
+                    digests: TargetDigest::of_content(&content),
+                    content: None,
+                });
+            }
+        }
+
+        let mut peers: Vec<(PeerID, Peer)> = self.connected_peers.lock().unwrap().drain().collect();
+        rand::thread_rng().shuffle(&mut peers);
+        let nodes: Vec<Node> = peers
+            .iter()
+            .map(|&(id, ref p)| Node {
+                id: id,
+                endpoint: p.endpoint,
+            })
+            .collect();
+
+        let mut handed_over = handover.is_empty();
+        for (node, &(_, ref peer)) in nodes.iter().zip(peers.iter()) {
+            // Older peers would drop the connection on a message they don't know anyway.
+            if peer.version < LEAVE_VERSION {
+                println!("leave: {:?} speaks version {}, not sending leave", node.id, peer.version);
+                peer.close();
+                continue;
+            }
+            let replacements: Vec<Node> = nodes
+                .iter()
+                .filter(|n| n.id != node.id)
+                .take(MAX_REPLACEMENTS)
+                .cloned()
+                .collect();
+            // The first peer we can reach that knows how to fetch it takes over.
+            let takes_over = !handed_over && peer.version >= HANDOVER_VERSION;
+            let resp = if takes_over {
+                peer.leave(replacements, handover.clone())
+            } else {
+                peer.leave(replacements, Vec::new())
+            };
+            match resp {
+                Ok(_) if takes_over => {
+                    println!("leave: handed over {} selections to {:?}", handover.len(), node.id);
+                    handed_over = true;
                 }
-                println!("set_clipboard: send successful");
+                Ok(_) => (),
+                Err(e) => println!("leave: unable to send leave to {:?}: {}", node.id, e),
             }
         }
+        if !handed_over {
+            println!("leave: unable to hand over, our clipboard is lost");
+        }
+        for (_, peer) in peers {
+            peer.join();
+        }
+    }
 
-        Ok(())
+    /// Stops this node: the accepting, pinging and maintenance threads are stopped, the overlay
+    /// is left as with `leave`, and all threads are joined before this returns.
+    /// The node does not accept connections or keep in touch with other peers afterwards.
+    pub fn shutdown(&self) {
+        self.stop();
+        println!("shutdown: leaving");
+        self.leave();
+        println!("shutdown: done");
+    }
+
+    /// Stops this node like `shutdown`, but without leaving, as if it crashed.
+    #[cfg(test)]
+    pub fn crash(&self) {
+        self.stop();
+        for (_, peer) in self.connected_peers.lock().unwrap().drain() {
+            peer.close();
+        }
+    }
+
+    /// Stops accepting connections, as if this node was behind a firewall, but keeps talking to
+    /// the peers it is connected to.
+    #[cfg(test)]
+    pub fn firewall(&self) {
+        self.stop();
+    }
+
+    /// Stops the accepting, pinging and maintenance threads, waits for the accepted connections
+    /// to be handled and closes the listener.
+    fn stop(&self) {
+        println!("shutdown: stopping background threads");
+        self.shutdown.signal();
+        let threads: Vec<thread::JoinHandle<()>> = self.threads.lock().unwrap().drain(..).collect();
+        for t in threads {
+            if t.join().is_err() {
+                println!("shutdown: background thread panicked");
+            }
+        }
+        // The accepting thread is gone, so no handlers are added anymore.
+        let handlers: Vec<thread::JoinHandle<()>> =
+            self.handlers.lock().unwrap().drain(..).collect();
+        for t in handlers {
+            if t.join().is_err() {
+                println!("shutdown: connection handler panicked");
+            }
+        }
+        // Peers trying to connect are refused right away instead of waiting in the backlog.
+        self.sock.lock().unwrap().take();
     }
 
-    /// Attempts to get the latest clipboard.
+    /// Attempts to get the latest content of [selection].
     /// If the own node holds the latest clipboard, ok(None) is returned, because the local clipboard should not point to this application but rather to the (local) source.
     /// Otherwise a CopyConnection is opened to the peer who is assumed to have the latest
-    /// clipboard.
-    pub fn get_clipboard(&self) -> Result<Option<String>, Box<Error>> {
+    /// clipboard, requesting it in one of [targets], most preferred first.
+    /// If that peer can't be reached, the clipboard is fetched from a peer that announced a
+    /// replica of it instead, or requested from the peer who copied it through the overlay.
+    /// If the peer who copied announced digests of the content, the content is checked against
+    /// them, and not fetched at all if we already have it.
+    pub fn get_clipboard(
+        &self,
+        selection: Selection,
+        targets: &[String],
+    ) -> Result<Option<ClipboardContent>, Box<Error>> {
+        let selection_state = self.selections.get(selection);
         // TODO strictly speaking this is racy
-        let overlay_state = self.state.lock().unwrap().clone();
-        if overlay_state.last_copy_src.eq(&self.own_id) {
+        let overlay_state = selection_state.state.lock().unwrap().clone();
+        if overlay_state.last_copy_src.eq(&self.own.id) {
             //println!("get_clipboard: I'm the owner, returning local clipboard");
-            return Ok(None);
+            // Unless we took it over from a leaving peer, then the local clipboard is outdated.
+            return Ok(selection_state.taken_over.lock().unwrap().take());
         }
         //if the state hasn't changed, return the last content.
         {
-            let cached_state = self.cache_state.lock().unwrap().clone();
+            let cached_state = selection_state.cache_state.lock().unwrap().clone();
             if cached_state == overlay_state{
                 println!("get_clipboard: Cache is recent and valid, returning cached clipboard");
-                return Ok(Some(self.cached_clipboard.lock().unwrap().clone()));
+                return Ok(Some(selection_state.cached_clipboard.lock().unwrap().clone()));
+            }
+        }
+
+        let digests = selection_state.digests_of(&overlay_state);
+        let content = match selection_state.find_local(targets, &digests) {
+            Some(content) => {
+                println!("get_clipboard: already have content matching the announced digests");
+                content
+            }
+            None => {
+                let content = self.fetch_any(selection, targets, &overlay_state)?;
+                verify_digests(&content, &digests)?;
+                content
+            }
+        };
+
+        {
+            let mut s = selection_state.cached_clipboard.lock().unwrap();
+            *s = content.clone();
+            // The state may have moved on during the transfer, the content belongs to this one.
+            let mut c = selection_state.cache_state.lock().unwrap();
+            *c = overlay_state.clone();
+        }
+
+        // Others may get the content from us now, in the target we prefer ourselves.
+        if let Some(target) = content.best_target(targets) {
+            let digest = ContentDigest::of(&target, content.get(&target).unwrap());
+            self.publish_replica(Replica {
+                selection: selection,
+                state: overlay_state,
+                holder: self.own,
+                target: target,
+                digest: digest,
+            });
+        }
+        Ok(Some(content))
+    }
+
+    /// Requests [selection] at [overlay_state] from the peer that copied it, or from a peer that
+    /// announced a replica of it if that fails, or through the overlay from the peer that copied
+    /// it if we can't reach any of them directly.
+    fn fetch_any(
+        &self,
+        selection: Selection,
+        targets: &[String],
+        overlay_state: &CopyClock,
+    ) -> Result<ClipboardContent, Box<Error>> {
+        let source = Node {
+            id: overlay_state.last_copy_src,
+            endpoint: overlay_state.last_copy_endpoint,
+        };
+        println!(
+            "get_clipboard: getting clipboard from {:?} at {:?}",
+            source.id, source.endpoint
+        );
+        match self.fetch_resuming(selection, targets, &source) {
+            Ok(content) => Ok(content),
+            Err(e) => {
+                println!("get_clipboard: unable to get clipboard from source: {}", e);
+                if *self.selections.get(selection).state.lock().unwrap() != *overlay_state {
+                    // Probably the source told us about a newer state, the next paste gets that.
+                    return Err(e);
+                }
+                if let Some(content) = self.fetch_replica(selection, targets, overlay_state) {
+                    return Ok(content);
+                }
+                println!("get_clipboard: relaying request to {:?} through the overlay", source.id);
+                self.fetch_relayed(selection, targets, overlay_state).map_err(|relay_err| {
+                    println!("get_clipboard: unable to get clipboard relayed: {}", relay_err);
+                    e
+                })
             }
         }
+    }
+
+    /// Requests [selection] at [overlay_state] through the overlay from the peer that copied it:
+    /// a RelayRequest is sent to that peer if it is one of ours, or to all of them otherwise, and
+    /// the answer is routed back along the path the request took.
+    /// An answer for another state is not accepted.
+    fn fetch_relayed(
+        &self,
+        selection: Selection,
+        targets: &[String],
+        overlay_state: &CopyClock,
+    ) -> Result<ClipboardContent, Box<Error>> {
+        let source = overlay_state.last_copy_src;
+        let message_id = generate_message_id();
+        // Our own request may come back to us through other peers.
+        self.seen_copy_notifications
+            .lock()
+            .unwrap()
+            .insert(message_id);
+        let responses = self.relays.wait(message_id);
+        let sent = {
+            let peers = self.connected_peers.lock().unwrap();
+            let next: Vec<&Peer> = match peers.get(&source) {
+                Some(p) => vec![p],
+                None => peers.values().collect(),
+            };
+            next.iter()
+                .filter(|p| {
+                    p.relay_request(
+                        source,
+                        selection,
+                        targets.to_vec(),
+                        self.config.compression.clone(),
+                        self.config.max_chunk(),
+                        message_id,
+                        RELAY_REQUEST_TTL,
+                        0,
+                    ).is_ok()
+                })
+                .count()
+        };
+        let resp = if sent == 0 {
+            Err(From::from("no peers to relay through"))
+        } else {
+            self.receive_relayed(selection, overlay_state, &responses)
+        };
+        self.relays.done(&message_id);
+        resp
+    }
+
+    /// Receives the answer to our own RelayRequest for [selection] at [overlay_state] from
+    /// [responses].
+    fn receive_relayed(
+        &self,
+        selection: Selection,
+        overlay_state: &CopyClock,
+        responses: &Receiver<RelayAnswer>,
+    ) -> Result<ClipboardContent, Box<Error>> {
+        let transfer = receive_transfer(&self.selections, selection, overlay_state, responses)?;
+        let (len, wire_len) = (transfer.len, transfer.total_len);
+        let mut content = ClipboardContent::new();
+        content.insert(&transfer.target.clone(), transfer.content()?);
 
+        let mut stats = self.stats.lock().unwrap();
+        stats.received += len;
+        stats.received_wire += wire_len;
         println!(
-            "get_clipboard: getting clipboard from {:?}",
-            overlay_state.last_copy_src
+            "->relay: received {} bytes as {} bytes, overall compression ratio {:.2}",
+            len,
+            wire_len,
+            stats.compression_ratio()
         );
+
+        Ok(content)
+    }
+
+    /// Requests [selection] from [source], resuming interrupted streamed transfers.
+    fn fetch_resuming(
+        &self,
+        selection: Selection,
+        targets: &[String],
+        source: &Node,
+    ) -> Result<ClipboardContent, Box<Error>> {
+        let mut transfer = None;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.fetch_clipboard(selection, targets, source, &mut transfer) {
+                Ok(content) => return Ok(content),
+                // Only streamed transfers that got somewhere are worth resuming.
+                Err(e) => match transfer {
+                    Some(ref t) if attempts < MAX_TRANSFER_ATTEMPTS => println!(
+                        "->copy: transfer interrupted at {}/{} bytes, resuming: {}",
+                        t.received(),
+                        t.total_len,
+                        e
+                    ),
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
+
+    /// Attempts to get [selection] at [overlay_state] in one of [targets] from the peers that
+    /// announced a replica of it, checking the content against the digest the source announced.
+    fn fetch_replica(
+        &self,
+        selection: Selection,
+        targets: &[String],
+        overlay_state: &CopyClock,
+    ) -> Option<ClipboardContent> {
+        let selection_state = self.selections.get(selection);
+        // Only the source can tell what the content is, the holders of replicas might lie.
+        let digests = selection_state.digests_of(overlay_state);
+        if digests.is_empty() {
+            println!("get_clipboard: source announced no digests, not trusting replicas");
+            return None;
+        }
+        let mut replicas: Vec<Replica> = selection_state
+            .replicas
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.state == *overlay_state && targets.contains(&r.target))
+            .cloned()
+            .collect();
+        // Most preferred target first.
+        replicas.sort_by_key(|r| targets.iter().position(|t| *t == r.target));
+
+        for replica in replicas {
+            println!(
+                "get_clipboard: getting replica from {:?} at {:?}",
+                replica.holder.id, replica.holder.endpoint
+            );
+            let digest = match digests.iter().find(|d| d.target == replica.target) {
+                Some(digest) if digest.digest == replica.digest => digest,
+                _ => {
+                    println!(
+                        "get_clipboard: replica of {} does not match what the source announced",
+                        replica.target
+                    );
+                    continue;
+                }
+            };
+            let resp = self.fetch_resuming(selection, &[replica.target.clone()], &replica.holder);
+            let content = match resp {
+                Ok(content) => content,
+                Err(e) => {
+                    println!("get_clipboard: unable to get replica: {}", e);
+                    continue;
+                }
+            };
+            match content.get(&replica.target) {
+                Some(data) if digest.matches(data) => return Some(content),
+                _ => println!("get_clipboard: replica does not match {:?}", digest.digest),
+            }
+        }
+        None
+    }
+
+    /// Announces to all peers that this node holds [replica].
+    fn publish_replica(&self, replica: Replica) {
+        let message_id = generate_message_id();
+        self.seen_copy_notifications
+            .lock()
+            .unwrap()
+            .insert(message_id);
+        let peers = self.connected_peers.lock().unwrap();
+        for (id, p) in peers.iter() {
+            let resp = p.notify_replica(replica.clone(), message_id, REPLICA_NOTIFICATION_TTL, 0);
+            if let Err(e) = resp {
+                println!("get_clipboard: unable to announce replica to {:?}: {}", id, e);
+            }
+        }
+    }
+
+    /// Requests [selection] from [source], resuming [transfer] if it holds the beginning of the
+    /// clipboard from an earlier attempt.
+    /// [transfer] is cleared if the remote replies with an error.
+    fn fetch_clipboard(
+        &self,
+        selection: Selection,
+        targets: &[String],
+        source: &Node,
+        transfer: &mut Option<Transfer>,
+    ) -> Result<ClipboardContent, Box<Error>> {
+        let selection_state = self.selections.get(selection);
+        let offset = transfer.as_ref().map_or(0, |t| t.received());
         let mut conn = CopyConnection::open(
-            &self.own_id,
-            &overlay_state.last_copy_src,
-            &"text".to_string(),
+            &self.own,
+            &source.endpoint,
+            selection,
+            targets,
+            offset,
+            &self.config,
         )?;
+        // The endpoint may have been taken over by someone else since.
+        if conn.remote_id()? != source.id {
+            return Err(From::from(format!(
+                "{:?} is not {:?} anymore",
+                source.endpoint, source.id
+            )));
+        }
 
         let msg = conn.read_message()?;
-        println!("->copy: received response: {:?}", msg);
 
         if let MessageType::ErrorResponse { state, error } = msg.message_type {
             println!(
                 "->copy: received error response, err: {}, state: {:?}",
                 error, state
             );
-            let new_state = update_state(self.state.clone(), state);
+            *transfer = None;
+            let new_state = update_state(&selection_state.state, state, self.selections.policy);
             println!("->copy: updated own state to {:?}", new_state);
             return Err(From::from(format!("remote  replied with error: {}", error)));
         }
-        if let MessageType::TextResponse { text } = msg.message_type {
-            let mut s=self.cached_clipboard.lock().unwrap();
-            *s=text.clone();
-            let mut c= self.cache_state.lock().unwrap();
-            *c = self.state.lock().unwrap().clone();
-            return Ok(Some(text));
-        }
+        let (content, len, wire_len) = match msg.message_type {
+            MessageType::TextResponse { text } => {
+                let len = text.len() as u64;
+                (ClipboardContent::from_text(&text), len, len)
+            }
+            MessageType::BinaryResponse { target, data } => {
+                let len = data.len() as u64;
+                let mut content = ClipboardContent::new();
+                content.insert(&target, data);
+                (content, len, len)
+            }
+            MessageType::StreamHeader { .. } => {
+                println!("->copy: received stream header: {:?}", msg.message_type);
+                let mut reported = 0;
+                conn.read_stream(msg, transfer, &mut |received, total| {
+                    // Report every tenth of the transfer.
+                    let tenths = if total == 0 { 10 } else { received * 10 / total };
+                    if tenths > reported || received == total {
+                        reported = tenths;
+                        println!("->copy: received {}/{} bytes", received, total);
+                    }
+                })?;
+                let t = transfer.take().unwrap();
+                let (len, wire_len) = (t.len, t.total_len);
+                let mut content = ClipboardContent::new();
+                content.insert(&t.target.clone(), t.content()?);
+                (content, len, wire_len)
+            }
+            _ => {
+                println!("->copy: received invalid response, got: {:?}", msg);
+                return Err(From::from("remote sent an invalid reply, check logs"));
+            }
+        };
 
-        println!("->copy: received invalid response, got: {:?}", msg);
-        Err(From::from("remote sent an invalid reply, check logs"))
+        let mut stats = self.stats.lock().unwrap();
+        stats.received += len;
+        stats.received_wire += wire_len;
+        println!(
+            "->copy: received {} bytes as {} bytes, overall compression ratio {:.2}",
+            len,
+            wire_len,
+            stats.compression_ratio()
+        );
+
+        Ok(content)
     }
 
     fn perform_join_single(&self, mut conn: JoinConnection) {
@@ -372,7 +2112,7 @@ impl Overlay {
             let msg = msg.unwrap();
 
             if let MessageType::JoinResponse { target } = msg.message_type {
-                if !target.eq(&self.own_id) {
+                if !target.eq(&self.own.id) {
                     println!(
                         "->join: got wrong target, dropping connection. Got: {:?}",
                         msg
@@ -380,8 +2120,11 @@ impl Overlay {
                     return;
                 }
 
-                let mut available = self.available_ids.lock().unwrap();
-                available.push(msg.src_id);
+                let mut available = self.available_peers.lock().unwrap();
+                available.push(Node {
+                    id: msg.src_id,
+                    endpoint: msg.src_endpoint,
+                });
             } else {
                 println!(
                     "->join: received wrong message type, dropping connection. Got: {:?}",
@@ -392,85 +2135,217 @@ impl Overlay {
         }
     }
 
-    /// Joins the overlay network, using the bootstrap peers given at construction time.
-    pub fn perform_join(&self) -> Result<(), Box<Error>> {
+    /// Floods JoinRequests through [endpoints], adding the peers that respond to the available
+    /// peers.
+    fn discover(&self, endpoints: &[Endpoint]) {
         // TODO make this  parallel
-        // TODO only take n peers for this, not all of them
-        for i in 0..self.bootstrap_ids.len() {
-            let id = self.bootstrap_ids.get(i).unwrap();
-
-            let mut join_conn = JoinConnection::open(&self.own_id, id, 8);
+        for ep in endpoints.iter() {
+            let mut join_conn = JoinConnection::open(&self.own, ep, 8, &self.config);
             match join_conn {
                 Ok(mut conn) => {
-                    println!("->join: opened a connection to {:?}", id);
+                    println!("->join: opened a connection to {:?}", ep);
                     self.perform_join_single(conn);
                 }
                 Err(e) => {
-                    println!("->join: unable to connect to {:?}: {}", id, e);
+                    println!("->join: unable to connect to {:?}: {}", ep, e);
                     continue;
                 }
             }
         }
 
-        let mut available = self.available_ids.lock().unwrap();
+        let mut available = self.available_peers.lock().unwrap();
         available.as_mut_slice().sort();
-        available.dedup();
+        available.dedup_by_key(|p| p.id);
         println!("->join: got these peers: {:?}", *available);
-        if available.len() == 0 {
-            return Err(From::from("I know no peers"));
-        }
-
-        let state = self.state.lock().unwrap().clone();
+    }
 
-        // Establish P2P connections with a bunch of peers
-        {
-            let mut peers = self.connected_peers.lock().unwrap();
+    /// Opens P2P connections to available peers we are not connected to yet, in random order,
+    /// until we are connected to `max_degree` peers.
+    /// Peers we are unable to connect to are no longer considered available.
+    fn connect_available(&self) {
+        let (state, primary) = self.selections.states();
+        // Connecting blocks, so only the candidates are taken under the locks.
+        let mut candidates = self.available_peers.lock().unwrap().clone();
+        rand::thread_rng().shuffle(&mut candidates);
 
-            // TODO only take a bunch of peers, not all of them
-            for i in 0..available.len() {
-                let p = available[i];
-                println!("->join: building p2p connection to peer at {:?}", p);
-                let mut p2p_conn = P2PConnection::open(&self.own_id, &p, &state);
-                if let Err(e) = p2p_conn {
-                    println!("->join: unable to open connection: {}", e);
+        for p in candidates {
+            {
+                let peers = self.connected_peers.lock().unwrap();
+                if peers.len() >= self.max_degree {
+                    break;
+                }
+                if p.id == self.own.id || peers.contains_key(&p.id) {
                     continue;
                 }
-                let mut p2p_conn = p2p_conn.unwrap();
-                let peer = Peer::new(
-                    p2p_conn,
-                    self.own_id.clone(),
-                    p.clone(),
-                    self.connected_peers.clone(),
-                    self.state.clone(),
-                );
-                if let Err(e) = peer {
-                    println!("->join: unable to construct peer: {}", e);
+            }
+            println!("->join: building p2p connection to peer at {:?}", p);
+            let mut p2p_conn =
+                P2PConnection::open(&self.own, &p.endpoint, &state, &primary, &self.config);
+            if let Err(e) = p2p_conn {
+                println!("->join: unable to open connection: {}", e);
+                self.available_peers.lock().unwrap().retain(|a| a.id != p.id);
+                continue;
+            }
+            let mut p2p_conn = p2p_conn.unwrap();
+            match p2p_conn.remote_id() {
+                Ok(id) if id == p.id => (),
+                _ => {
+                    println!("->join: peer at {:?} is not {:?}, skipping", p.endpoint, p.id);
+                    self.available_peers.lock().unwrap().retain(|a| a.id != p.id);
                     continue;
                 }
-                let peer = peer.unwrap();
-                peers.insert(p.clone(), peer);
-                println!("->join: p2p connection successful");
             }
 
-            if peers.len() == 0 {
-                return Err(From::from("Not connected to any peers"));
+            let mut peers = self.connected_peers.lock().unwrap();
+            // The peer may have connected to us in the meantime.
+            match peers.get(&p.id) {
+                Some(existing) if !replaces(existing, false, self.own.id, p.id) => {
+                    println!("->join: already connected to {:?}, dropping connection", p.id);
+                    continue;
+                }
+                Some(_) => (),
+                None if peers.len() >= self.max_degree => {
+                    println!("->join: connected to enough peers, dropping connection");
+                    continue;
+                }
+                None => (),
+            }
+            let peer = Peer::new(
+                p2p_conn,
+                self.own.clone(),
+                p.id,
+                p.endpoint,
+                self.connected_peers.clone(),
+                self.available_peers.clone(),
+                self.selections.clone(),
+                self.seen_copy_notifications.clone(),
+                self.relays.clone(),
+                self.config.clone(),
+            );
+            if let Err(e) = peer {
+                println!("->join: unable to construct peer: {}", e);
+                continue;
+            }
+            let peer = peer.unwrap();
+            if let Some(old) = peers.insert(p.id, peer) {
+                println!("->join: replacing the other connection to {:?}", p.id);
+                old.close();
             }
+            self.selections.departed.lock().unwrap().remove(&p.id);
+            println!("->join: p2p connection successful");
+        }
+    }
+
+    /// Joins the overlay network, using the bootstrap peers given at construction time.
+    pub fn perform_join(&self) -> Result<(), Box<Error>> {
+        self.discover(&self.bootstrap_endpoints);
+        if self.available_peers.lock().unwrap().len() == 0 {
+            return Err(From::from("I know no peers"));
+        }
+
+        self.connect_available();
+        if self.connected_peers.lock().unwrap().len() == 0 {
+            return Err(From::from("Not connected to any peers"));
         }
 
         Ok(())
     }
 
+    /// Keeps the number of connected peers between `min_degree` and `max_degree`.
+    /// If there are too few, more peers are discovered through the connected and bootstrap
+    /// peers and connected to. If there are too many, randomly chosen ones are dropped.
+    fn maintain_degree(&self) {
+        let degree = self.connected_peers.lock().unwrap().len();
+        if degree < self.min_degree {
+            println!(
+                "maintenance: degree {} is below {}, looking for peers",
+                degree, self.min_degree
+            );
+            let mut endpoints: Vec<Endpoint> = self.connected_peers
+                .lock()
+                .unwrap()
+                .values()
+                .map(|p| p.endpoint)
+                .collect();
+            endpoints.extend_from_slice(&self.bootstrap_endpoints);
+            self.discover(&endpoints);
+            self.connect_available();
+        } else if degree > self.max_degree {
+            println!(
+                "maintenance: degree {} is above {}, dropping peers",
+                degree, self.max_degree
+            );
+            let mut peers = self.connected_peers.lock().unwrap();
+            let mut ids: Vec<PeerID> = peers.keys().cloned().collect();
+            rand::thread_rng().shuffle(&mut ids);
+            for id in ids.iter().take(degree - self.max_degree) {
+                println!("maintenance: dropping {:?}", id);
+                if let Some(peer) = peers.remove(id) {
+                    peer.close();
+                }
+            }
+        }
+    }
+
+    /// Starts a thread to periodically check the degree of [overlay] and find new peers or drop
+    /// surplus ones.
+    pub fn start_maintenance(overlay: Arc<Overlay>) {
+        let shutdown = overlay.shutdown.clone();
+        let o = overlay.clone();
+        let t = thread::spawn(move || {
+            while !shutdown.sleep(time::Duration::from_secs(MAINTENANCE_INTERVAL_SECS)) {
+                o.maintain_degree();
+                o.log_status();
+            }
+        });
+        overlay.threads.lock().unwrap().push(t);
+    }
+
+    /// Logs how much clipboard content was exchanged so far and how many flooded messages were
+    /// dropped as duplicates.
+    fn log_status(&self) {
+        let (joins, notifications) = self.seen_stats();
+        println!(
+            "status: seen JoinRequests: {} new, {} duplicates, {} remembered",
+            joins.misses, joins.hits, joins.len
+        );
+        println!(
+            "status: seen CopyNotifications: {} new, {} duplicates, {} remembered",
+            notifications.misses, notifications.hits, notifications.len
+        );
+        let stats = self.stats();
+        println!(
+            "status: sent {} bytes ({} on the wire), received {} bytes ({} on the wire), \
+             compression ratio {:.2}",
+            stats.sent,
+            stats.sent_wire,
+            stats.received,
+            stats.received_wire,
+            stats.compression_ratio()
+        );
+    }
+
+    /// Sets the number of peers to stay connected to.
+    /// With fewer than [min] peers, more are looked for; surplus peers above [max] are dropped.
+    pub fn set_degree(&mut self, min: usize, max: usize) {
+        self.min_degree = min;
+        self.max_degree = cmp::max(min, max);
+    }
+
     /// Starts a thread to periodically ping connected peers for soft-state.
     pub fn start_autoping(&self) {
         let peers = self.connected_peers.clone();
-        let own_id = self.own_id.clone();
-        let state = self.state.clone();
-        thread::spawn(move || {
-            thread::sleep_ms(rand::thread_rng().gen_range(1000, 5000));
+        let selections = self.selections.clone();
+        let shutdown = self.shutdown.clone();
+        let t = thread::spawn(move || {
+            let delay = rand::thread_rng().gen_range(1000, 5000);
+            if shutdown.sleep(time::Duration::from_millis(delay)) {
+                return;
+            }
             loop {
                 {
-                    let mut p: Vec<Endpoint> = Vec::new();
-                    let mut peers_to_remove: Vec<Endpoint> = Vec::new();
+                    let mut p: Vec<PeerID> = Vec::new();
+                    let mut peers_to_remove: Vec<PeerID> = Vec::new();
                     let mut peers = peers.lock().unwrap();
                     for peer in peers.keys() {
                         p.push(peer.clone());
@@ -481,9 +2356,10 @@ impl Overlay {
                         let peer = peers.get(&p[i]).unwrap();
 
                         println!("ping: sending ping to {:?}", p[i]);
-                        let resp = peer.ping(state.lock().unwrap().clone());
+                        let (state, primary) = selections.states();
+                        let resp = peer.ping(state, primary, selections.departed_peers());
                         if let Err(e) = resp {
-                            println!("ping: unable to send, removing peer: {}", e);
+                            println!("ping: peer is dead, removing: {}", e);
                             peer.close();
                             peers_to_remove.push(p[i].clone());
                             continue;
@@ -491,141 +2367,332 @@ impl Overlay {
                         println!("ping: ping successful");
                     }
 
+                    let mut departed = selections.departed.lock().unwrap();
                     for i in 0..peers_to_remove.len() {
                         peers.remove(&peers_to_remove[i]);
+                        departed.insert(peers_to_remove[i]);
                     }
                 }
 
                 println!("ping: done pinging all peers, sleeping");
 
-                thread::sleep(time::Duration::new(10, 0));
+                if shutdown.sleep(time::Duration::from_secs(PING_INTERVAL_SECS)) {
+                    return;
+                }
             }
         });
+        self.threads.lock().unwrap().push(t);
     }
 
     /// Starts a thread to accept incoming connections.
+    /// The listener is polled, so that the thread notices when the node shuts down.
     pub fn start_accepting(&self) {
         let s = self.sock.clone();
         let peers = self.connected_peers.clone();
-        let own_id = self.own_id.clone();
-        let state = self.state.clone();
-        let clipboard = self.clipboard.clone();
+        let own = self.own.clone();
+        let selections = self.selections.clone();
         let seen_message_ids = self.seen_join_message_ids.clone();
-        thread::spawn(move || {
+        let seen_copy_notifications = self.seen_copy_notifications.clone();
+        let relays = self.relays.clone();
+        let available_peers = self.available_peers.clone();
+        let config = self.config.clone();
+        let stats = self.stats.clone();
+        let shutdown = self.shutdown.clone();
+        let handlers = self.handlers.clone();
+        let max_degree = self.max_degree;
+        let t = thread::spawn(move || {
             let mut sock = s.lock().unwrap();
-            loop {
-                let mut incoming = accept(&mut sock).unwrap();
+            let sock = match sock.as_mut() {
+                Some(sock) => sock,
+                None => {
+                    println!("accept: listener is closed, not accepting");
+                    return;
+                }
+            };
+            if let Err(e) = sock.set_nonblocking(true) {
+                println!("accept: unable to poll listener, not accepting: {}", e);
+                return;
+            }
+            while !shutdown.is_signalled() {
+                let mut incoming = match accept(sock, &config) {
+                    Ok(incoming) => incoming,
+                    Err(e) => {
+                        let idle = match e.downcast_ref::<io::Error>() {
+                            Some(e) => e.kind() == io::ErrorKind::WouldBlock,
+                            None => false,
+                        };
+                        if !idle {
+                            println!("accept: unable to accept: {}", e);
+                        }
+                        shutdown.sleep(time::Duration::from_millis(ACCEPT_POLL_INTERVAL_MS));
+                        continue;
+                    }
+                };
                 println!(
                     "Incoming connection: {:?}, first message: {:?}",
                     incoming.conn, incoming.first_msg
                 );
 
-                match incoming.conn {
+                let handler = match incoming.conn {
                     Connection::P2P(mut c) => {
                         Overlay::handle_p2p_connection(
                             c,
-                            own_id.clone(),
+                            own.clone(),
                             incoming.first_msg.src_id.clone(),
+                            incoming.first_msg.src_endpoint.clone(),
                             peers.clone(),
-                            state.clone(),
-                        );
+                            available_peers.clone(),
+                            selections.clone(),
+                            seen_copy_notifications.clone(),
+                            relays.clone(),
+                            config.clone(),
+                            shutdown.clone(),
+                            max_degree,
+                        )
                     }
                     Connection::Copy(mut c) => {
                         Overlay::handle_copy_connection(
                             c,
-                            own_id.clone(),
-                            state.clone(),
-                            clipboard.clone(),
-                        );
+                            incoming.first_msg.clone(),
+                            own.clone(),
+                            selections.clone(),
+                            stats.clone(),
+                            shutdown.clone(),
+                        )
                     }
                     Connection::Join(mut c) => {
                         Overlay::handle_join_connection(
                             c,
                             peers.clone(),
-                            own_id.clone(),
+                            own.clone(),
                             incoming.first_msg.clone(),
                             seen_message_ids.clone(),
-                        );
+                            config.clone(),
+                            shutdown.clone(),
+                        )
                     }
-                }
+                };
+                let mut handlers = handlers.lock().unwrap();
+                handlers.retain(|t| !t.is_finished());
+                handlers.push(handler);
             }
+            println!("accept: stopped");
         });
+        self.threads.lock().unwrap().push(t);
     }
 
     fn handle_copy_connection(
         mut c: CopyConnection,
-        own_id: PeerID,
-        state: Arc<Mutex<CopyClock>>,
-        clipboard: Arc<Mutex<String>>,
-    ) {
+        msg: Message,
+        own: Node,
+        selections: Arc<Selections>,
+        stats: Arc<Mutex<TransferStats>>,
+        shutdown: Arc<ShutdownSignal>,
+    ) -> thread::JoinHandle<()> {
         thread::spawn(move || {
-            let state_copy = state.lock().unwrap().clone();
-
-            if !state_copy.last_copy_src.eq(&own_id) {
-                println!("<-copy: I don't have the latest clipboard, replying error");
-                let resp = c.respond_error(
-                    &"I don't have the latest clipboard".to_string(),
-                    &state_copy,
-                    &own_id,
-                );
-                match resp {
-                    Ok(_) => println!("<-copy: reply successful"),
-                    Err(e) => println!("<-copy: unable to reply: {}", e),
-                }
-
+            if shutdown.is_signalled() {
+                println!("<-copy: shutting down, closing");
+                c.close();
+                return;
+            }
+            // Shutdown waits for us, don't let a stalled requester block it.
+            let timeout = time::Duration::from_secs(PEER_WRITE_TIMEOUT_SECS);
+            if let Err(e) = c.set_timeouts(timeout, timeout) {
+                println!("<-copy: unable to set timeouts, closing: {}", e);
                 c.close();
                 return;
             }
+            // Legacy CopyRequests are answered in one piece.
+            let (targets, selection, stream) = match msg.message_type {
+                MessageType::CopyRequest {
+                    targets, selection, ..
+                } => (targets, selection, None),
+                MessageType::StreamRequest {
+                    targets,
+                    selection,
+                    offset,
+                    max_chunk,
+                    compression,
+                } => (targets, selection, Some((offset, max_chunk, compression))),
+                _ => (Vec::new(), Selection::Clipboard, None),
+            };
+            let selection_state = selections.get(selection);
+
+            let (clipboard_copy, state_copy) = match selection_state.servable(&own) {
+                Ok((clipboard, state)) => {
+                    if !state.last_copy_src.eq(&own.id) {
+                        println!("<-copy: serving replica of {:?}", state.last_copy_src);
+                    }
+                    (clipboard, state)
+                }
+                Err(state) => {
+                    println!("<-copy: I don't have the latest clipboard, replying error");
+                    let resp = c.respond_error(
+                        &"I don't have the latest clipboard".to_string(),
+                        &state,
+                        &own,
+                    );
+                    match resp {
+                        Ok(_) => println!("<-copy: reply successful"),
+                        Err(e) => println!("<-copy: unable to reply: {}", e),
+                    }
+
+                    c.close();
+                    return;
+                }
+            };
 
-            let clipboard_copy = clipboard.lock().unwrap().clone();
-            println!("<-copy: sending TextResponse...");
-            let resp = c.respond(&clipboard_copy, &own_id);
+            let resp = if targets.is_empty() {
+                // The requester predates target negotiation and only understands text.
+                match clipboard_copy.text() {
+                    Some(text) => {
+                        println!("<-copy: sending TextResponse...");
+                        c.respond(&text, &own)
+                    }
+                    None => {
+                        println!("<-copy: clipboard has no text, replying error");
+                        c.respond_error(
+                            &"clipboard has no text content".to_string(),
+                            &state_copy,
+                            &own,
+                        )
+                    }
+                }
+            } else {
+                match clipboard_copy.best_target(&targets) {
+                    Some(target) => match stream {
+                        Some((offset, max_chunk, compression)) => {
+                            println!(
+                                "<-copy: streaming target {} from offset {}...",
+                                target, offset
+                            );
+                            let data = clipboard_copy.get(&target).unwrap();
+                            c.respond_stream(
+                                &target,
+                                data,
+                                offset,
+                                max_chunk,
+                                &compression,
+                                &state_copy,
+                                &own,
+                            ).map(|(len, wire_len)| {
+                                let mut stats = stats.lock().unwrap();
+                                stats.sent += len;
+                                stats.sent_wire += wire_len;
+                                println!(
+                                    "<-copy: sent {} bytes as {} bytes, overall compression ratio {:.2}",
+                                    len,
+                                    wire_len,
+                                    stats.compression_ratio()
+                                );
+                            })
+                        }
+                        None => {
+                            println!("<-copy: sending BinaryResponse for target {}...", target);
+                            c.respond_binary(&target, clipboard_copy.get(&target).unwrap(), &own)
+                        }
+                    },
+                    None => {
+                        println!(
+                            "<-copy: none of {:?} available (have {:?}), replying error",
+                            targets,
+                            clipboard_copy.targets()
+                        );
+                        c.respond_error(
+                            &"none of the requested targets are available".to_string(),
+                            &state_copy,
+                            &own,
+                        )
+                    }
+                }
+            };
             match resp {
                 Ok(_) => println!("<-copy: reply successful"),
                 Err(e) => println!("<-copy: unable to reply: {}", e),
             }
 
             c.close();
-        });
+        })
     }
 
     fn handle_p2p_connection(
         mut c: P2PConnection,
-        own_id: PeerID,
+        own: Node,
         remote_id: PeerID,
-        peers: Arc<Mutex<HashMap<Endpoint, Peer>>>,
-        state: Arc<Mutex<CopyClock>>,
-    ) {
+        remote_endpoint: Endpoint,
+        peers: Arc<Mutex<HashMap<PeerID, Peer>>>,
+        available_peers: Arc<Mutex<Vec<Node>>>,
+        selections: Arc<Selections>,
+        seen_copy_notifications: Arc<Mutex<SeenCache<MessageID>>>,
+        relays: Arc<Relays>,
+        config: Arc<ConnectionConfig>,
+        shutdown: Arc<ShutdownSignal>,
+        max_degree: usize,
+    ) -> thread::JoinHandle<()> {
         thread::spawn(move || {
+            let own_id = own.id;
             // TODO update state
-            let peer = Peer::new(c, own_id, remote_id.clone(), peers.clone(), state.clone());
+            let peer = Peer::new(
+                c,
+                own,
+                remote_id.clone(),
+                remote_endpoint,
+                peers.clone(),
+                available_peers,
+                selections.clone(),
+                seen_copy_notifications,
+                relays,
+                config,
+            );
             if let Err(e) = peer {
                 println!("<-p2p: unable to construct peer: {}", e);
                 return;
             }
             let peer = peer.unwrap();
 
+            // Checked while holding the peers, so that leaving during shutdown sees this peer.
             let mut peers = peers.lock().unwrap();
-            peers.insert(remote_id, peer);
-        });
+            if shutdown.is_signalled() {
+                println!("<-p2p: shutting down, closing");
+                peer.close();
+                return;
+            }
+            match peers.get(&remote_id) {
+                Some(existing) if !replaces(existing, true, own_id, remote_id) => {
+                    println!("<-p2p: already connected to {:?}, closing", remote_id);
+                    peer.close();
+                    return;
+                }
+                Some(_) => (),
+                None if peers.len() >= max_degree => {
+                    println!("<-p2p: connected to {} peers already, closing", peers.len());
+                    peer.close();
+                    return;
+                }
+                None => (),
+            }
+            if let Some(old) = peers.insert(remote_id, peer) {
+                println!("<-p2p: replacing the other connection to {:?}", remote_id);
+                old.close();
+            }
+            selections.departed.lock().unwrap().remove(&remote_id);
+        })
     }
 
     fn handle_join_connection(
         mut c: JoinConnection,
         peers: Arc<Mutex<HashMap<PeerID, Peer>>>,
-        own_id: PeerID,
+        own: Node,
         msg: Message,
-        seen_message_ids: Arc<Mutex<HashMap<MessageID, ()>>>,
-    ) {
+        seen_message_ids: Arc<Mutex<SeenCache<MessageID>>>,
+        config: Arc<ConnectionConfig>,
+        shutdown: Arc<ShutdownSignal>,
+    ) -> thread::JoinHandle<()> {
         thread::spawn(move || {
-            {
-                let mut seen_message_ids = seen_message_ids.lock().unwrap();
-                if seen_message_ids.contains_key(&msg.message_id) {
-                    println!("<-join: I already saw this message ID, closing connection");
-                    c.close();
-                    return;
-                }
-                seen_message_ids.insert(msg.message_id, ());
+            if !seen_message_ids.lock().unwrap().insert(msg.message_id) {
+                println!("<-join: I already saw this message ID, closing connection");
+                c.close();
+                return;
             }
 
             if msg.ttl <= 1 {
@@ -633,7 +2700,7 @@ impl Overlay {
                     "<-join: msg.ttl is {}, will just reply and close connection",
                     msg.ttl
                 );
-                let resp = c.respond(&own_id, &msg);
+                let resp = c.respond(&own, &msg);
                 match resp {
                     Ok(_) => println!("<-join: reply successful"),
                     Err(e) => println!("<-join: unable to reply: {}", e),
@@ -645,8 +2712,8 @@ impl Overlay {
             let mut p: Vec<Endpoint> = Vec::new();
             {
                 let peers = peers.lock().unwrap();
-                for peer in peers.keys() {
-                    p.push(peer.clone());
+                for peer in peers.values() {
+                    p.push(peer.endpoint.clone());
                 }
             }
             println!(
@@ -656,8 +2723,12 @@ impl Overlay {
 
             // TODO make this parallel
             for ep in p.iter() {
+                if shutdown.is_signalled() {
+                    println!("<-join: shutting down, not forwarding anymore");
+                    break;
+                }
                 println!("<-join: forwarding to {:?}", ep);
-                let mut conn = JoinConnection::forward(&ep, &msg);
+                let mut conn = JoinConnection::forward(&ep, &msg, &config);
                 if let Err(e) = conn {
                     println!("<-join: unable to forward: {}", e);
                     continue;
@@ -694,12 +2765,12 @@ impl Overlay {
             }
 
             println!("<-join: done forwarding, responding with own ID");
-            let resp = c.respond(&own_id, &msg);
+            let resp = c.respond(&own, &msg);
             match resp {
                 Ok(_) => println!("<-join: reply successful"),
                 Err(e) => println!("<-join: unable to reply: {}", e),
             }
             c.close();
-        });
+        })
     }
 }
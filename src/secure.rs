@@ -0,0 +1,436 @@
+use std::cmp;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use blake2::{Blake2s256, Digest};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use hex;
+use serde_json;
+use snow;
+
+use network::PeerID;
+
+/// The Noise protocol used for every connection.
+/// XX exchanges and authenticates both static keys, psk3 additionally requires both sides to know
+/// the pre-shared key, which is what makes a node a member of the overlay.
+const NOISE_PARAMS: &'static str = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s";
+
+/// The maximum size of a Noise message, including the authentication tag.
+const MAX_NOISE_MESSAGE: usize = 65535;
+
+/// The size of the authentication tag appended to every encrypted frame.
+const TAG_LEN: usize = 16;
+
+/// How long a peer may take to complete the handshake before it is dropped.
+const HANDSHAKE_TIMEOUT_SECS: u64 = 5;
+
+/// Keys holds the key material used to authenticate to other peers: the pre-shared key of the
+/// overlay and the static keypair of this node, whose public key is the PeerID of the node.
+pub struct Keys {
+    psk: [u8; 32],
+    private: Vec<u8>,
+    id: PeerID,
+}
+
+/// An Identity is the static keypair of a node, as persisted on disk.
+#[derive(Serialize, Deserialize)]
+struct Identity {
+    private: String,
+    public: String,
+}
+
+impl Identity {
+    fn generate() -> Result<Identity, Box<Error>> {
+        let keypair = snow::Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+        Ok(Identity {
+            private: hex::encode(&keypair.private),
+            public: hex::encode(&keypair.public),
+        })
+    }
+
+    /// Loads the identity stored at [path], generating and storing a new one if there is none.
+    fn load_or_generate(path: &PathBuf) -> Result<Identity, Box<Error>> {
+        if path.exists() {
+            let identity: Identity = serde_json::from_slice(&fs::read(path)?)
+                .map_err(|e| format!("unable to parse identity {}: {}", path.display(), e))?;
+            return Ok(identity);
+        }
+
+        println!("secure: generating new identity at {}", path.display());
+        let identity = Identity::generate()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Only we may read the private key.
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        file.write_all(&serde_json::to_vec(&identity)?)?;
+        Ok(identity)
+    }
+}
+
+impl Keys {
+    fn new(passphrase: &str, identity: Identity) -> Result<Keys, Box<Error>> {
+        if passphrase.is_empty() {
+            return Err(From::from("the pre-shared key must not be empty"));
+        }
+        let mut psk = [0u8; 32];
+        psk.copy_from_slice(&Blake2s256::digest(passphrase.as_bytes()));
+
+        Ok(Keys {
+            psk: psk,
+            private: hex::decode(&identity.private)?,
+            id: PeerID::from_slice(&hex::decode(&identity.public)?)?,
+        })
+    }
+
+    /// Creates Keys for the overlay with the given [passphrase] and a newly generated identity,
+    /// which is not persisted.
+    pub fn from_passphrase(passphrase: &str) -> Result<Keys, Box<Error>> {
+        Keys::new(passphrase, Identity::generate()?)
+    }
+
+    /// Loads the pre-shared key from PASTE_ANYWHERE_PSK or, if that is not set, from
+    /// ~/.paste-anywhere/psk.
+    /// The identity is loaded from PASTE_ANYWHERE_IDENTITY or ~/.paste-anywhere/identity, and
+    /// generated on first use.
+    pub fn load() -> Result<Keys, Box<Error>> {
+        let identity_path = match env::var_os("PASTE_ANYWHERE_IDENTITY") {
+            Some(path) => PathBuf::from(path),
+            None => config_path("identity")
+                .ok_or("PASTE_ANYWHERE_IDENTITY is not set and HOME is unknown")?,
+        };
+        let identity = Identity::load_or_generate(&identity_path)?;
+
+        if let Ok(passphrase) = env::var("PASTE_ANYWHERE_PSK") {
+            return Keys::new(&passphrase, identity);
+        }
+
+        let path = config_path("psk").ok_or("PASTE_ANYWHERE_PSK is not set and HOME is unknown")?;
+        let passphrase = fs::read_to_string(&path).map_err(|e| {
+            format!(
+                "no pre-shared key: PASTE_ANYWHERE_PSK is not set and {} is unreadable: {}",
+                path.display(),
+                e
+            )
+        })?;
+        Keys::new(passphrase.trim(), identity)
+    }
+
+    /// Returns the PeerID of this node.
+    pub fn id(&self) -> PeerID {
+        self.id
+    }
+
+    fn builder(&self) -> Result<snow::Builder, Box<Error>> {
+        Ok(snow::Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(&self.private)
+            .psk(3, &self.psk))
+    }
+}
+
+/// Returns the path of [name] in ~/.paste-anywhere.
+fn config_path(name: &str) -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".paste-anywhere").join(name))
+}
+
+/// The transport keys established by a handshake, shared between clones of a SecureStream.
+/// The nonces are counted separately for each direction; the send nonce is held locked while the
+/// frames of a write are written, so they hit the wire in nonce order and in one piece even with
+/// several writers.
+struct Transport {
+    noise: snow::StatelessTransportState,
+    send_nonce: Mutex<u64>,
+    recv_nonce: Mutex<u64>,
+}
+
+/// A SecureStream is a TCP stream encrypted and authenticated with Noise.
+/// Data is sent in frames of a 2-byte length followed by the encrypted payload, but Read and
+/// Write hide that, so it can be used like the TcpStream it wraps.
+/// Every write is sent as a whole, so a message written at once is never interleaved with the
+/// writes of a duplicate of the stream.
+pub struct SecureStream {
+    stream: TcpStream,
+    remote_static: Vec<u8>,
+    transport: Arc<Transport>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+impl fmt::Debug for SecureStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecureStream({:?})", self.stream)
+    }
+}
+
+impl SecureStream {
+    /// Connects to [addr] and performs the handshake as the initiator.
+    pub fn connect(addr: SocketAddr, keys: &Keys) -> Result<SecureStream, Box<Error>> {
+        let stream = TcpStream::connect(addr)?;
+        let handshake = keys.builder()?.build_initiator()?;
+        SecureStream::handshake(stream, handshake)
+    }
+
+    /// Performs the handshake as the responder on an accepted [stream].
+    /// This fails if the remote does not know the pre-shared key.
+    pub fn accept(stream: TcpStream, keys: &Keys) -> Result<SecureStream, Box<Error>> {
+        let handshake = keys.builder()?.build_responder()?;
+        SecureStream::handshake(stream, handshake)
+    }
+
+    fn handshake(
+        mut stream: TcpStream,
+        mut handshake: snow::HandshakeState,
+    ) -> Result<SecureStream, Box<Error>> {
+        // Don't let a silent peer block us forever.
+        stream.set_read_timeout(Some(time::Duration::from_secs(HANDSHAKE_TIMEOUT_SECS)))?;
+
+        let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+        while !handshake.is_handshake_finished() {
+            if handshake.is_my_turn() {
+                let n = handshake.write_message(&[], &mut buf)?;
+                write_frame(&mut stream, &buf[..n])?;
+            } else {
+                let frame = read_frame(&mut stream)?;
+                handshake
+                    .read_message(&frame, &mut buf)
+                    .map_err(|e| format!("handshake failed, wrong pre-shared key? {}", e))?;
+            }
+        }
+
+        stream.set_read_timeout(None)?;
+        let remote_static = handshake
+            .get_remote_static()
+            .ok_or("handshake did not yield a remote key")?
+            .to_vec();
+        Ok(SecureStream {
+            stream: stream,
+            remote_static: remote_static,
+            transport: Arc::new(Transport {
+                noise: handshake.into_stateless_transport_mode()?,
+                send_nonce: Mutex::new(0),
+                recv_nonce: Mutex::new(0),
+            }),
+            read_buf: Vec::new(),
+            read_pos: 0,
+        })
+    }
+
+    /// Duplicates the stream, so one thread can read while another one writes.
+    /// Only one of the copies may read.
+    pub fn try_clone(&self) -> io::Result<SecureStream> {
+        Ok(SecureStream {
+            stream: self.stream.try_clone()?,
+            remote_static: self.remote_static.clone(),
+            transport: self.transport.clone(),
+            read_buf: Vec::new(),
+            read_pos: 0,
+        })
+    }
+
+    /// Sets the read and write timeouts of the underlying TcpStream, which are shared with all
+    /// duplicates of this stream.
+    /// A read that times out leaves the stream in an undefined state, it should be closed.
+    pub fn set_timeouts(
+        &self,
+        read: Option<time::Duration>,
+        write: Option<time::Duration>,
+    ) -> io::Result<()> {
+        self.stream.set_read_timeout(read)?;
+        self.stream.set_write_timeout(write)
+    }
+
+    /// Shuts down the underlying TcpStream, including all duplicates of this stream.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Both)
+    }
+
+    /// Returns the static public key the remote authenticated with.
+    pub fn remote_static(&self) -> &[u8] {
+        &self.remote_static
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+}
+
+fn write_frame<W: Write>(w: &mut W, frame: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(frame.len() + 2);
+    buf.write_u16::<BigEndian>(frame.len() as u16)?;
+    buf.extend_from_slice(frame);
+    w.write_all(&buf)
+}
+
+fn read_frame<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = r.read_u16::<BigEndian>()?;
+    let mut frame = vec![0u8; len as usize];
+    r.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+fn noise_error(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl Read for SecureStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read_pos == self.read_buf.len() {
+            let frame = match read_frame(&mut self.stream) {
+                Ok(frame) => frame,
+                // The connection was closed between frames.
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(e) => return Err(e),
+            };
+            let mut payload = vec![0u8; frame.len()];
+            let mut nonce = self.transport.recv_nonce.lock().unwrap();
+            let n = self.transport
+                .noise
+                .read_message(*nonce, &frame, &mut payload)
+                .map_err(noise_error)?;
+            *nonce += 1;
+            payload.truncate(n);
+            self.read_buf = payload;
+            self.read_pos = 0;
+        }
+
+        let n = cmp::min(buf.len(), self.read_buf.len() - self.read_pos);
+        buf[..n].copy_from_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for SecureStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let frames = buf.len() / (MAX_NOISE_MESSAGE - TAG_LEN) + 1;
+        let mut wire = Vec::with_capacity(buf.len() + frames * (TAG_LEN + 2));
+        let mut frame = vec![0u8; MAX_NOISE_MESSAGE];
+
+        // Holding the nonce until everything is written keeps other writers out of the middle.
+        let mut nonce = self.transport.send_nonce.lock().unwrap();
+        for payload in buf.chunks(MAX_NOISE_MESSAGE - TAG_LEN) {
+            let len = self.transport
+                .noise
+                .write_message(*nonce, payload, &mut frame)
+                .map_err(noise_error)?;
+            *nonce += 1;
+            write_frame(&mut wire, &frame[..len])?;
+        }
+        self.stream.write_all(&wire)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::{Identity, Keys, SecureStream};
+
+    /// Connects a client with [client] keys to a server with [server] keys and sends a message
+    /// larger than a single Noise frame through it.
+    fn roundtrip(client: Keys, server: Keys) -> Result<Vec<u8>, String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = SecureStream::accept(stream, &server).map_err(|e| e.to_string())?;
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).map_err(|e| e.to_string())?;
+            Ok(data)
+        });
+
+        if let Ok(mut stream) = SecureStream::connect(addr, &client) {
+            // The client only learns about a wrong key when the server hangs up.
+            let _ = stream.write_all(&vec![42u8; 100000]);
+        }
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_same_psk_connects() {
+        let data = roundtrip(
+            Keys::from_passphrase("hunter2").unwrap(),
+            Keys::from_passphrase("hunter2").unwrap(),
+        ).unwrap();
+        assert_eq!(data, vec![42u8; 100000]);
+    }
+
+    #[test]
+    fn test_identity_is_persisted() {
+        let path = ::std::env::temp_dir().join(format!(
+            "paste-anywhere-test-{}/identity",
+            ::std::process::id()
+        ));
+        let first = Keys::new("psk", Identity::load_or_generate(&path).unwrap()).unwrap();
+        let second = Keys::new("psk", Identity::load_or_generate(&path).unwrap()).unwrap();
+        assert_eq!(first.id(), second.id());
+        ::std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_writes_are_not_interleaved() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream =
+                SecureStream::accept(stream, &Keys::from_passphrase("hunter2").unwrap()).unwrap();
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).unwrap();
+            data
+        });
+
+        // Each message spans several frames.
+        let keys = Keys::from_passphrase("hunter2").unwrap();
+        let stream = SecureStream::connect(addr, &keys).unwrap();
+        let writers: Vec<_> = (1..3u8)
+            .map(|b| {
+                let mut stream = stream.try_clone().unwrap();
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        stream.write_all(&vec![b; 100000]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+        stream.shutdown().unwrap();
+
+        let data = server.join().unwrap();
+        assert_eq!(data.len(), 20 * 100000);
+        for message in data.chunks(100000) {
+            assert!(message.iter().all(|b| *b == message[0]));
+        }
+    }
+
+    #[test]
+    fn test_wrong_psk_is_rejected() {
+        let res = roundtrip(
+            Keys::from_passphrase("hunter2").unwrap(),
+            Keys::from_passphrase("hunter3").unwrap(),
+        );
+        assert!(res.is_err());
+    }
+}
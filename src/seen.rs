@@ -0,0 +1,118 @@
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// SeenStats are the counters of a SeenCache, for diagnostics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SeenStats {
+    /// How many items were seen before.
+    pub hits: u64,
+    /// How many items were new.
+    pub misses: u64,
+    /// How many items are currently remembered.
+    pub len: usize,
+}
+
+/// A SeenCache remembers recently seen items, e.g. the IDs of flooded messages, so that every
+/// message is handled at most once.
+/// Items are forgotten after [ttl], or earlier, oldest first, if more than [capacity] items are
+/// seen within that time.
+pub struct SeenCache<T: Hash + Eq + Copy> {
+    items: HashSet<T>,
+    order: VecDeque<(T, Instant)>,
+    capacity: usize,
+    ttl: Duration,
+    hits: u64,
+    misses: u64,
+}
+
+impl<T: Hash + Eq + Copy> SeenCache<T> {
+    /// Creates an empty SeenCache holding at most [capacity] items for at most [ttl].
+    pub fn new(capacity: usize, ttl: Duration) -> SeenCache<T> {
+        SeenCache {
+            items: HashSet::new(),
+            order: VecDeque::new(),
+            capacity: capacity,
+            ttl: ttl,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Records [item] as seen, returning true if it was not seen before.
+    pub fn insert(&mut self, item: T) -> bool {
+        let now = Instant::now();
+        self.expire(now);
+        if self.items.contains(&item) {
+            self.hits += 1;
+            return false;
+        }
+        self.misses += 1;
+
+        if self.order.len() >= self.capacity {
+            if let Some((oldest, _)) = self.order.pop_front() {
+                self.items.remove(&oldest);
+            }
+        }
+        self.items.insert(item);
+        self.order.push_back((item, now));
+        true
+    }
+
+    /// Returns the number of items currently remembered.
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Returns the counters of this cache.
+    pub fn stats(&self) -> SeenStats {
+        SeenStats {
+            hits: self.hits,
+            misses: self.misses,
+            len: self.len(),
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(item, seen)) = self.order.front() {
+            if now.duration_since(seen) < self.ttl {
+                break;
+            }
+            self.order.pop_front();
+            self.items.remove(&item);
+        }
+    }
+}
+
+#[test]
+fn seen_cache_is_bounded() {
+    let mut cache = SeenCache::new(2, Duration::from_secs(60));
+    assert!(cache.insert(1));
+    assert!(!cache.insert(1));
+    assert!(cache.insert(2));
+    assert!(cache.insert(3));
+    assert_eq!(cache.len(), 2);
+
+    // 1 was evicted to make room for 3.
+    assert!(cache.insert(1));
+    assert!(!cache.insert(3));
+    assert_eq!(
+        cache.stats(),
+        SeenStats {
+            hits: 2,
+            misses: 4,
+            len: 2,
+        }
+    );
+}
+
+#[test]
+fn seen_cache_expires() {
+    use std::thread;
+
+    let mut cache = SeenCache::new(100, Duration::from_millis(50));
+    assert!(cache.insert(1));
+    assert!(!cache.insert(1));
+    thread::sleep(Duration::from_millis(60));
+    assert!(cache.insert(1));
+}
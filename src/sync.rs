@@ -0,0 +1,351 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use clipboard::{ClipboardBackend, SyncMode, DEFAULT_TARGETS};
+use overlay::Overlay;
+
+/// How often the overlay is asked for the latest remote selections, in milliseconds.
+const POLL_INTERVAL_MS: u64 = 197;
+
+/// Synchronizes the local selections in [backend] with [overlay] according to [mode].
+/// Remote changes are polled from the overlay in a background thread and written to the backend.
+/// Local changes reported by the backend are published to the overlay.
+/// This blocks until the backend stops reporting changes.
+pub fn run(overlay: Arc<Overlay>, mut backend: Box<ClipboardBackend>, mode: SyncMode) {
+    let changes = backend.subscribe();
+    let backend = Arc::new(Mutex::new(backend));
+
+    {
+        let overlay = overlay.clone();
+        let backend = backend.clone();
+        let targets: Vec<String> = DEFAULT_TARGETS.iter().map(|t| t.to_string()).collect();
+        thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_millis(POLL_INTERVAL_MS));
+            for selection in mode.synced() {
+                let resp = overlay.get_clipboard(selection, &targets);
+                match resp {
+                    Err(e) => println!("sync: unable to get {:?}: {}", selection, e),
+                    Ok(Some(content)) => for local in mode.apply_to(selection) {
+                        let resp = backend.lock().unwrap().set(local, content.clone());
+                        if let Err(e) = resp {
+                            println!("sync: unable to set local {:?}: {}", local, e);
+                        }
+                    },
+                    // We hold the latest content ourselves.
+                    Ok(None) => (),
+                };
+            }
+        });
+    }
+
+    for (selection, content) in changes.iter() {
+        println!("sync: local {:?} is: {}", selection, content);
+        if let Some(synced) = mode.publish_as(selection) {
+            if let Err(e) = overlay.set_clipboard(synced, &content) {
+                println!("sync: unable to publish local {:?}: {}", selection, e);
+            }
+        }
+    }
+    println!("sync: backend stopped reporting changes");
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use clipboard::{ClipboardContent, MemoryClipboard, Selection, SyncMode, TEXT_TARGET};
+    use overlay::Overlay;
+    use network::{ConflictPolicy, ConnectionConfig};
+    use secure::Keys;
+    use super::run;
+
+    /// Starts a node on localhost without a clipboard.
+    fn start_overlay(bootstrap: Vec<::network::Endpoint>) -> Arc<Overlay> {
+        start_overlay_with(bootstrap, ConflictPolicy::Clock)
+    }
+
+    /// Starts a node on localhost without a clipboard, resolving conflicts with [policy].
+    fn start_overlay_with(
+        bootstrap: Vec<::network::Endpoint>,
+        policy: ConflictPolicy,
+    ) -> Arc<Overlay> {
+        let mut config = ConnectionConfig::new(Keys::from_passphrase("test").unwrap());
+        config.conflict_policy = policy;
+        let localhost = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let o = Arc::new(Overlay::new(&localhost, 0, bootstrap, config).unwrap());
+        o.start_accepting();
+        if let Err(e) = o.perform_join() {
+            println!("test: join failed: {}", e);
+        }
+        o
+    }
+
+    /// Starts a node on localhost with an in-memory clipboard, returning the overlay and a handle
+    /// to the clipboard.
+    fn start_node(bootstrap: Vec<::network::Endpoint>) -> (Arc<Overlay>, MemoryClipboard) {
+        let o = start_overlay(bootstrap);
+
+        let cb = MemoryClipboard::new();
+        {
+            let o = o.clone();
+            let backend = Box::new(cb.clone());
+            thread::spawn(move || run(o, backend, SyncMode::Both));
+        }
+        // Copies before that would go unnoticed.
+        wait_for("the node to watch its clipboard", || cb.has_subscribers());
+        (o, cb)
+    }
+
+    /// Polls [condition] until it holds or fails after a few seconds, saying it was waiting for
+    /// [what].
+    fn wait_for<F: FnMut() -> bool>(what: &str, mut condition: F) {
+        let start = Instant::now();
+        while !condition() {
+            if start.elapsed() > Duration::from_secs(10) {
+                panic!("timed out waiting for {}", what);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Waits until [selection] in [cb] holds [expected] or fails after a few seconds.
+    fn await_content(cb: &MemoryClipboard, selection: Selection, expected: &ClipboardContent) {
+        wait_for(&format!("{:?} to hold {:?}", selection, expected), || {
+            cb.paste(selection) == *expected
+        });
+    }
+
+    /// Waits until [nodes] know the current state of [selection] at [source].
+    fn await_state(source: &Overlay, nodes: &[&Overlay], selection: Selection) {
+        let state = source.state(selection);
+        wait_for(&format!("{:?} to reach all nodes", state), || {
+            nodes.iter().all(|o| o.state(selection) == state)
+        });
+    }
+
+    /// Waits until [node] is connected to [count] peers.
+    fn await_peers(node: &Overlay, count: usize) {
+        wait_for(&format!("{} peers", count), || node.peer_count() == count);
+    }
+
+    #[test]
+    fn test_three_nodes_converge() {
+        let (a, cb_a) = start_node(Vec::new());
+        let (_b, cb_b) = start_node(vec![a.endpoint()]);
+        let (_c, cb_c) = start_node(vec![a.endpoint()]);
+        // Joining returns before the accepting side has registered the new P2P connections,
+        // notifications sent in the meantime would not reach the new node.
+        await_peers(&a, 2);
+
+        let hello = ClipboardContent::from_text("hello");
+        cb_b.copy(Selection::Clipboard, hello.clone());
+        await_content(&cb_a, Selection::Clipboard, &hello);
+        await_content(&cb_c, Selection::Clipboard, &hello);
+
+        let world = ClipboardContent::from_text("world");
+        cb_c.copy(Selection::Primary, world.clone());
+        await_content(&cb_a, Selection::Primary, &world);
+        await_content(&cb_b, Selection::Primary, &world);
+        // PRIMARY is synchronized independently.
+        assert_eq!(cb_a.paste(Selection::Clipboard), hello);
+    }
+
+    #[test]
+    fn test_clipboard_survives_leave() {
+        let a = start_overlay(Vec::new());
+        let (b, cb_b) = start_node(vec![a.endpoint()]);
+        await_peers(&a, 1);
+
+        let bye = ClipboardContent::from_text("bye");
+        cb_b.copy(Selection::Clipboard, bye.clone());
+        wait_for("the copy to reach a", || {
+            a.state(Selection::Clipboard).last_copy_src == b.id()
+        });
+        // Returns once a fetched the handover and closed the connection.
+        b.leave();
+
+        // a took over: it hands out the content once for its local clipboard and is the source
+        // from now on.
+        let targets = vec![TEXT_TARGET.to_string()];
+        assert_eq!(a.get_clipboard(Selection::Clipboard, &targets).unwrap(), Some(bye));
+        assert_eq!(a.get_clipboard(Selection::Clipboard, &targets).unwrap(), None);
+    }
+
+    #[test]
+    fn test_paste_from_replica() {
+        let a = start_overlay(Vec::new());
+        let b = start_overlay(vec![a.endpoint()]);
+        let c = start_overlay(vec![a.endpoint()]);
+        await_peers(&a, 2);
+
+        // Too large to be pushed.
+        let hello = ClipboardContent::from_text(&"hello".repeat(1000));
+        let targets = vec![TEXT_TARGET.to_string()];
+        a.set_clipboard(Selection::Clipboard, &hello).unwrap();
+        await_state(&a, &[&b, &c], Selection::Clipboard);
+        // b fetches from a and announces that it holds a replica now.
+        let pasted = b.get_clipboard(Selection::Clipboard, &targets).unwrap();
+        assert_eq!(pasted, Some(hello.clone()));
+        wait_for("the replica to reach c", || {
+            c.replica_count(Selection::Clipboard) > 0
+        });
+
+        // a is gone without handing over, c gets the content from b instead.
+        a.crash();
+        let pasted = c.get_clipboard(Selection::Clipboard, &targets).unwrap();
+        assert_eq!(pasted, Some(hello));
+        assert!(c.stats().received > 0);
+    }
+
+    #[test]
+    fn test_paste_relayed() {
+        let a = start_overlay(Vec::new());
+        let b = start_overlay(vec![a.endpoint()]);
+        let c = start_overlay(vec![a.endpoint()]);
+        await_peers(&a, 2);
+
+        // Too large to be pushed.
+        let hello = ClipboardContent::from_text(&"hello".repeat(1000));
+        let targets = vec![TEXT_TARGET.to_string()];
+        a.set_clipboard(Selection::Clipboard, &hello).unwrap();
+        await_state(&a, &[&b, &c], Selection::Clipboard);
+
+        // c can't connect to a anymore, the content reaches it over the P2P connections.
+        a.firewall();
+        let pasted = c.get_clipboard(Selection::Clipboard, &targets).unwrap();
+        assert_eq!(pasted, Some(hello));
+        assert!(c.stats().received > 0);
+    }
+
+    #[test]
+    fn test_small_clipboard_is_pushed() {
+        let a = start_overlay(Vec::new());
+        let b = start_overlay(vec![a.endpoint()]);
+        await_peers(&a, 1);
+
+        let hello = ClipboardContent::from_text("hello");
+        let targets = vec![TEXT_TARGET.to_string()];
+        a.set_clipboard(Selection::Clipboard, &hello).unwrap();
+        let state = a.state(Selection::Clipboard);
+        wait_for("the notification to reach b", || {
+            b.announced_state(Selection::Clipboard) == Some(state.clone())
+        });
+        assert_eq!(b.get_clipboard(Selection::Clipboard, &targets).unwrap(), Some(hello));
+        assert_eq!(b.stats().received, 0);
+    }
+
+    #[test]
+    fn test_known_content_is_not_fetched() {
+        let a = start_overlay(Vec::new());
+        let b = start_overlay(vec![a.endpoint()]);
+        await_peers(&a, 1);
+
+        // Too large to be pushed.
+        let hello = ClipboardContent::from_text(&"hello".repeat(1000));
+        let targets = vec![TEXT_TARGET.to_string()];
+        a.set_clipboard(Selection::Clipboard, &hello).unwrap();
+        await_state(&a, &[&b], Selection::Clipboard);
+        assert_eq!(b.get_clipboard(Selection::Clipboard, &targets).unwrap(), Some(hello.clone()));
+        let received = b.stats().received;
+        assert!(received > 0);
+
+        // Copying the same content again only changes the state.
+        a.set_clipboard(Selection::Clipboard, &hello).unwrap();
+        let state = a.state(Selection::Clipboard);
+        wait_for("the notification to reach b", || {
+            b.announced_state(Selection::Clipboard) == Some(state.clone())
+        });
+        assert_eq!(b.get_clipboard(Selection::Clipboard, &targets).unwrap(), Some(hello));
+        assert_eq!(b.stats().received, received);
+    }
+
+    #[test]
+    fn test_departed_peers_are_folded() {
+        let a = start_overlay(Vec::new());
+        let b = start_overlay(vec![a.endpoint()]);
+        // c learns of b from a, and connects to both.
+        await_peers(&a, 1);
+        let c = start_overlay(vec![a.endpoint()]);
+        await_peers(&a, 2);
+        await_peers(&c, 2);
+
+        let hello = ClipboardContent::from_text("hello");
+        c.set_clipboard(Selection::Clipboard, &hello).unwrap();
+        await_state(&c, &[&a, &b], Selection::Clipboard);
+        b.set_clipboard(Selection::Clipboard, &hello).unwrap();
+        await_state(&b, &[&a, &c], Selection::Clipboard);
+        let old = a.state(Selection::Clipboard);
+
+        c.leave();
+        await_peers(&a, 1);
+        // Only once b agrees that c is gone.
+        wait_for("b to report c as departed", || a.foldable_peers().contains(&c.id()));
+        a.set_clipboard(Selection::Clipboard, &hello).unwrap();
+        await_state(&a, &[&b], Selection::Clipboard);
+        // Without folding, c's entry would still be there. The new state replaced the old one
+        // everywhere anyway.
+        let state = a.state(Selection::Clipboard);
+        assert_eq!(state.clock.len(), 2);
+        assert_eq!(old.total_cmp(&state), ::std::cmp::Ordering::Less);
+    }
+
+    #[test]
+    fn test_latest_concurrent_copy_wins() {
+        // a and b don't know of each other when they copy.
+        let a = start_overlay_with(Vec::new(), ConflictPolicy::Latest);
+        let b = start_overlay_with(Vec::new(), ConflictPolicy::Latest);
+        let hello = ClipboardContent::from_text("hello");
+        a.set_clipboard(Selection::Clipboard, &hello).unwrap();
+        // The wall clock must move on between the copies.
+        thread::sleep(Duration::from_millis(50));
+        b.set_clipboard(Selection::Clipboard, &hello).unwrap();
+
+        // c learns of both copies from the pongs of a and b.
+        let c = start_overlay_with(vec![a.endpoint(), b.endpoint()], ConflictPolicy::Latest);
+        c.start_autoping();
+        await_state(&b, &[&c], Selection::Clipboard);
+        // The pong of a doesn't change c's mind either.
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(c.state(Selection::Clipboard), b.state(Selection::Clipboard));
+        c.shutdown();
+    }
+
+    #[test]
+    fn test_incoming_connections_respect_max_degree() {
+        let config = ConnectionConfig::new(Keys::from_passphrase("test").unwrap());
+        let localhost = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut a = Overlay::new(&localhost, 0, Vec::new(), config).unwrap();
+        a.set_degree(1, 1);
+        a.start_accepting();
+        let b = start_overlay(vec![a.endpoint()]);
+        await_peers(&a, 1);
+
+        // c learns of b from a, and connects to both.
+        let _c = start_overlay(vec![a.endpoint()]);
+        await_peers(&b, 2);
+        // The connection of c is refused, a never gets another peer.
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(a.peer_count(), 1);
+    }
+
+    #[test]
+    fn test_shutdown_joins_threads() {
+        let a = start_overlay(Vec::new());
+        let b = start_overlay(vec![a.endpoint()]);
+        for o in &[&a, &b] {
+            o.start_autoping();
+            Overlay::start_maintenance((*o).clone());
+        }
+        await_peers(&a, 1);
+
+        // The background threads sleep for much longer than this between their rounds.
+        let start = Instant::now();
+        b.shutdown();
+        a.shutdown();
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}